@@ -0,0 +1,286 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{Data, DeriveInput, Expr, ExprLit, Fields, GenericArgument, Lit, Meta, PathArguments, Type};
+
+// Generates TableMetadata, FromRow, TableRow and column/SQL constants for a struct, for every backend:
+//
+//   #[derive(BearTable)]
+//   #[table = "orders"]
+//   pub struct Order {
+//       pub id: OrderId,            // primary key is `id` unless a field has #[bear(id)]
+//       #[bear(rename = "total")]
+//       pub amount: i64,
+//       #[bear(version)]            // optional, enables update_fields_versioned
+//       pub version: i64,
+//       #[bear(deleted)]            // optional, Option<Instant>, enables soft delete
+//       pub deleted_at: Option<Instant>,
+//   }
+//
+// Replaces row_reader! plus the hand written TableMetadata impl. Generic structs are rejected, rows are concrete.
+// Structs with only the id column get no UPDATE_SQL/bind_update.
+#[proc_macro_derive(BearTable, attributes(table, bear))]
+pub fn derive_bear_table(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(it) => it.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+struct Column {
+    field: syn::Ident,
+    ty: Type,
+    name: String,
+    is_id: bool,
+    optional: bool,
+    is_version: bool,
+    is_deleted: bool,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let cls = &input.ident;
+    let table = table_name(&input)?;
+    // a row is a concrete table, generic columns would need Decode/Encode bounds for every backend
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&input.generics, "BearTable does not support generic structs"))
+    }
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(named) => &named.named,
+            _ => return Err(syn::Error::new_spanned(cls, "BearTable needs a struct with named fields")),
+        },
+        _ => return Err(syn::Error::new_spanned(cls, "BearTable needs a struct with named fields")),
+    };
+
+    let mut columns = Vec::new();
+    for field in fields {
+        check_type(&field.ty)?;
+        let ident = field.ident.clone().unwrap();
+        let mut col = Column { name: ident.to_string(), field: ident, ty: field.ty.clone(), is_id: false, optional: is_option(&field.ty), is_version: false, is_deleted: false };
+        for attr in field.attrs.iter().filter(|it| it.path().is_ident("bear")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("id") {
+                    col.is_id = true;
+                    Ok(())
+                } else if meta.path.is_ident("version") {
+                    col.is_version = true;
+                    Ok(())
+                } else if meta.path.is_ident("deleted") {
+                    col.is_deleted = true;
+                    Ok(())
+                } else if meta.path.is_ident("rename") {
+                    let lit: syn::LitStr = meta.value()?.parse()?;
+                    col.name = lit.value();
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `id`, `version`, `deleted` or `rename = \"...\"`"))
+                }
+            })?;
+        }
+        columns.push(col);
+    }
+
+    let id_col = match columns.iter().filter(|it| it.is_id).count() {
+        0 => columns.iter().find(|it| it.name == "id")
+            .ok_or_else(|| syn::Error::new_spanned(cls, "BearTable needs an `id` field or #[bear(id)] on the primary key"))?,
+        1 => columns.iter().find(|it| it.is_id).unwrap(),
+        _ => return Err(syn::Error::new_spanned(cls, "only one field can have #[bear(id)]")),
+    };
+
+    let version_col = match columns.iter().filter(|it| it.is_version).collect::<Vec<&Column>>().as_slice() {
+        [] => quote! { None },
+        [it] => { let name = &it.name; quote! { Some(#name) } },
+        _ => return Err(syn::Error::new_spanned(cls, "only one field can have #[bear(version)]")),
+    };
+
+    let deleted_col = match columns.iter().filter(|it| it.is_deleted).collect::<Vec<&Column>>().as_slice() {
+        [] => quote! { None },
+        [it] => { let name = &it.name; quote! { Some(#name) } },
+        _ => return Err(syn::Error::new_spanned(cls, "only one field can have #[bear(deleted)]")),
+    };
+
+    let names: Vec<&String> = columns.iter().map(|it| &it.name).collect();
+    let idents: Vec<&syn::Ident> = columns.iter().map(|it| &it.field).collect();
+    let tys: Vec<&Type> = columns.iter().map(|it| &it.ty).collect();
+    let id_name = &id_col.name;
+    let consts = columns.iter().map(|it| {
+        let cname = format_ident!("COL_{}", it.field.to_string().to_uppercase());
+        let name = &it.name;
+        quote! { pub const #cname: &'static str = #name; }
+    });
+
+    // $n placeholders work on every backend
+    let insert_sql = format!("INSERT INTO {table} ({}) VALUES ({})",
+                             names.iter().map(|it| it.as_str()).collect::<Vec<&str>>().join(", "),
+                             (1..=names.len()).map(|i| format!("${i}")).collect::<Vec<String>>().join(", "));
+    let update_sql = format!("UPDATE {table} SET {} WHERE {id_name} = ${}",
+                             names.iter().filter(|it| **it != id_name).enumerate().map(|(i, it)| format!("{it} = ${}", i + 1)).collect::<Vec<String>>().join(", "),
+                             names.len());
+    let update_binds: Vec<&syn::Ident> = columns.iter().filter(|it| it.name != *id_name).map(|it| &it.field).collect();
+    let id_field = &id_col.field;
+    // with only the id there is nothing to SET
+    let update = if update_binds.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            // binds all columns but the id, then the id
+            pub const UPDATE_SQL: &'static str = #update_sql;
+
+            pub fn bind_update<'q, A: ::sqlx::Arguments<'q>>(&'q self, args: &mut A)
+                where #( #tys: ::sqlx::Encode<'q, A::Database> + ::sqlx::Type<A::Database> + Sync ),*
+            {
+                #(
+                    ::sqlx::Arguments::add(args, &self.#update_binds);
+                )*
+                ::sqlx::Arguments::add(args, &self.#id_field);
+            }
+        }
+    };
+    // autoincrement ids are often Option
+    let id_value = if id_col.optional {
+        quote! { self.#id_field.as_ref().map(::std::string::ToString::to_string) }
+    } else {
+        quote! { Some(::std::string::ToString::to_string(&self.#id_field)) }
+    };
+    // without a value the id column is left out of inserts, postgres won't take NULL for a serial
+    let unset_id = if id_col.optional {
+        quote! {
+            fn id_unset(&self) -> bool {
+                self.#id_field.is_none()
+            }
+
+            fn bind_columns_without_id<'q>(&'q self, args: &mut <DB as ::sqlx::database::HasArguments<'q>>::Arguments) {
+                #(
+                    ::sqlx::Arguments::add(args, &self.#update_binds);
+                )*
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    Ok(quote! {
+        impl ::bear::table::TableMetadata for #cls {
+            fn table_name() -> &'static str { #table }
+            fn columns() -> &'static [&'static str] { &[ #(#names),* ] }
+            fn id_column() -> &'static str { #id_name }
+            fn version_column() -> Option<&'static str> { #version_col }
+            fn deleted_column() -> Option<&'static str> { #deleted_col }
+        }
+
+        impl<'r, R: ::sqlx::Row> ::sqlx::FromRow<'r, R> for #cls
+            where &'static str: ::sqlx::ColumnIndex<R>,
+                  #( #tys: ::sqlx::Decode<'r, R::Database> + ::sqlx::Type<R::Database> ),*
+        {
+            fn from_row(row: &'r R) -> Result<Self, ::sqlx::Error> {
+                Ok(Self {
+                    #(
+                        #idents: row.try_get(#names)?,
+                    )*
+                })
+            }
+        }
+
+        impl<DB: ::sqlx::Database> ::bear::table::TableRow<DB> for #cls
+            where #( #tys: for<'q> ::sqlx::Encode<'q, DB> + ::sqlx::Type<DB> + Sync ),*
+        {
+            fn bind_columns<'q>(&'q self, args: &mut <DB as ::sqlx::database::HasArguments<'q>>::Arguments) {
+                #(
+                    ::sqlx::Arguments::add(args, &self.#idents);
+                )*
+            }
+
+            fn id_value(&self) -> Option<String> {
+                #id_value
+            }
+
+            fn bind_id<'q>(&'q self, args: &mut <DB as ::sqlx::database::HasArguments<'q>>::Arguments) {
+                ::sqlx::Arguments::add(args, &self.#id_field);
+            }
+
+            #unset_id
+        }
+
+        impl #cls {
+            pub const TABLE: &'static str = #table;
+            #(#consts)*
+            pub const INSERT_SQL: &'static str = #insert_sql;
+            #update
+        }
+    })
+}
+
+fn table_name(input: &DeriveInput) -> syn::Result<String> {
+    let attr = input.attrs.iter().find(|it| it.path().is_ident("table"))
+        .ok_or_else(|| syn::Error::new(Span::call_site(), "BearTable needs #[table = \"name\"]"))?;
+    if let Meta::NameValue(nv) = &attr.meta {
+        if let Expr::Lit(ExprLit { lit: Lit::Str(s), .. }) = &nv.value {
+            return Ok(s.value())
+        }
+    }
+    Err(syn::Error::new_spanned(attr, "expected #[table = \"name\"]"))
+}
+
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(tp) => tp.path.segments.last().is_some_and(|it| it.ident == "Option"),
+        _ => false,
+    }
+}
+
+// neither sqlite nor postgres have unsigned 64 bit or 128 bit integers, and compound types need their own table
+const UNSUPPORTED: &[&str] = &["u64", "usize", "isize", "i128", "u128", "char", "HashMap", "HashSet", "BTreeMap", "BTreeSet", "Box", "Rc", "Arc"];
+
+fn check_type(ty: &Type) -> syn::Result<()> {
+    match ty {
+        Type::Path(tp) if tp.qself.is_none() => {
+            let last = tp.path.segments.last().unwrap();
+            let name = last.ident.to_string();
+            if UNSUPPORTED.contains(&name.as_str()) {
+                return Err(syn::Error::new_spanned(ty, format!("BearTable: `{name}` columns are not supported by the database")))
+            }
+            if let PathArguments::AngleBracketed(args) = &last.arguments {
+                let inner: Vec<&Type> = args.args.iter().filter_map(|it| match it {
+                    GenericArgument::Type(t) => Some(t),
+                    _ => None,
+                }).collect();
+                match (name.as_str(), inner.as_slice()) {
+                    ("Option", [t]) => return check_type(t),
+                    ("Vec", [Type::Path(t)]) if t.path.is_ident("u8") => return Ok(()),
+                    ("Vec", _) => return Err(syn::Error::new_spanned(ty, "BearTable: only Vec<u8> (blob) is supported")),
+                    _ => {}
+                }
+            }
+            Ok(())
+        },
+        _ => Err(syn::Error::new_spanned(ty, "BearTable: unsupported column type, use an owned type implementing sqlx::Type<Db>")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expanded(input: TokenStream2) -> String {
+        expand(syn::parse2(input).unwrap()).unwrap().to_string()
+    }
+
+    #[test]
+    fn update_sql_needs_a_column_besides_the_id() {
+        let it = expanded(quote! {
+            #[table = "tags"]
+            pub struct Tag { pub id: String }
+        });
+        assert!(it.contains("INSERT INTO tags (id) VALUES ($1)"), "{it}");
+        assert!(!it.contains("UPDATE_SQL") && !it.contains("bind_update"), "{it}");
+
+        let it = expanded(quote! {
+            #[table = "docs"]
+            pub struct Doc { pub id: String, pub body: String }
+        });
+        assert!(it.contains("UPDATE docs SET body = $1 WHERE id = $2"), "{it}");
+        assert!(it.contains("bind_update"), "{it}");
+    }
+}
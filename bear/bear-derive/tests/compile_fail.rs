@@ -0,0 +1,6 @@
+// BearTable rejects what it can't map with a compile error, the expected messages are in ui/*.stderr
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,10 @@
+use bear_derive::BearTable;
+
+#[derive(BearTable)]
+#[table = "things"]
+pub struct Thing<T> {
+    pub id: String,
+    pub value: T,
+}
+
+fn main() {}
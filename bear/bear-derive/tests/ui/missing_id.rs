@@ -0,0 +1,9 @@
+use bear_derive::BearTable;
+
+#[derive(BearTable)]
+#[table = "things"]
+pub struct Thing {
+    pub key: String,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use bear_derive::BearTable;
+
+#[derive(BearTable)]
+#[table = "things"]
+pub struct Thing {
+    pub id: String,
+    pub count: u64,
+}
+
+fn main() {}
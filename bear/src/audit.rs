@@ -0,0 +1,174 @@
+use std::rc::Rc;
+use actix_web::{web, HttpMessage, HttpRequest};
+use serde_json::{Map, Value};
+use crate::authmw::PrincipalInner;
+use crate::backend::{Args, Backend, BackendSql};
+use crate::db::DbWriteTxn;
+use crate::errors::ApiError;
+use crate::interface::AppContainer;
+use crate::utils::{gentoken, Instant};
+
+// Row audit log. With TxnMidFactory::with_audit::<AC>() the write transaction of a request carries an
+// AuditContext and every insert/update/delete done through the generic helpers in db (insert, upsert,
+// update_field(s), the versioned updates, delete_by_id, soft_delete, restore) adds a row_audit entry in
+// the same transaction, so the audit only has what was committed. Plain sqlx queries are not seen.
+
+// add to app migrations, in the version for the backend
+pub const ROW_AUDIT_SCHEMA: BackendSql = BackendSql { sqlite: ROW_AUDIT_SQLITE, postgres: ROW_AUDIT_POSTGRES };
+
+const ROW_AUDIT_SQLITE: &str = "
+CREATE TABLE row_audit (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    table_name TEXT NOT NULL,
+    row_id TEXT,
+    action TEXT NOT NULL,
+    old_values TEXT,
+    new_values TEXT,
+    principal TEXT,
+    impersonator TEXT,
+    request_id TEXT,
+    created INTEGER NOT NULL
+);
+CREATE INDEX row_audit_row ON row_audit(table_name, row_id, created);
+";
+
+const ROW_AUDIT_POSTGRES: &str = "
+CREATE TABLE row_audit (
+    id BIGSERIAL PRIMARY KEY,
+    table_name TEXT NOT NULL,
+    row_id TEXT,
+    action TEXT NOT NULL,
+    old_values TEXT,
+    new_values TEXT,
+    principal TEXT,
+    impersonator TEXT,
+    request_id TEXT,
+    created BIGINT NOT NULL
+);
+CREATE INDEX row_audit_row ON row_audit(table_name, row_id, created);
+";
+
+pub const AUDIT_INSERT: &str = "insert";
+pub const AUDIT_UPSERT: &str = "upsert";
+pub const AUDIT_UPDATE: &str = "update";
+pub const AUDIT_DELETE: &str = "delete";
+pub const AUDIT_SOFT_DELETE: &str = "soft_delete";
+pub const AUDIT_RESTORE: &str = "restore";
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Who is writing and when, attached to DbWriteTxn. Jobs can set one themselves with DbWriteTxn::set_audit:
+///
+///   txn.set_audit(AuditContext { principal: Some("job:retention".into()), ..AuditContext::new(objs.utcnow()) });
+#[derive(Debug, Clone)]
+pub struct AuditContext {
+    pub principal: Option<String>, // auth_kind:principal
+    pub impersonator: Option<String>,
+    pub request_id: Option<String>,
+    pub now: Instant, // row_audit.created, from the app clock
+}
+
+// reads the app clock from the request, see app_clock
+pub type AuditClock = fn(&HttpRequest) -> Option<Instant>;
+
+// AppContainer::utcnow of the app's web::Data<AC>
+pub(crate) fn app_clock<AC: AppContainer + 'static>(req: &HttpRequest) -> Option<Instant> {
+    req.app_data::<web::Data<AC>>().map(|it| it.utcnow())
+}
+
+// put in the request by TxnMiddleware when auditing is on
+#[derive(Clone, Copy)]
+pub(crate) struct AuditEnabled(pub(crate) AuditClock);
+
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+// from the X-Request-Id header, generated if missing. Stays the same for the whole request.
+pub fn request_id(req: &HttpRequest) -> String {
+    if let Some(rid) = req.extensions().get::<RequestId>() {
+        return rid.0.clone()
+    }
+    let rid: String = req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|it| it.to_str().ok())
+        .filter(|it| !it.is_empty() && it.len() <= 128)
+        .map(String::from)
+        .unwrap_or_else(gentoken);
+    req.extensions_mut().insert(RequestId(rid.clone()));
+    rid
+}
+
+impl AuditContext {
+    pub fn new(now: Instant) -> Self {
+        AuditContext { principal: None, impersonator: None, request_id: None, now }
+    }
+
+    // none when auditing is off for the request
+    pub(crate) fn from_request(req: &HttpRequest) -> Result<Option<AuditContext>, ApiError> {
+        let Some(AuditEnabled(clock)) = req.extensions().get::<AuditEnabled>().copied() else { return Ok(None) };
+        let now = clock(req)
+            .ok_or_else(|| ApiError::InvalidState(String::from("objs missing for audit")))?;
+        let rid = request_id(req);
+        let exts = req.extensions();
+        let principal = exts.get::<Rc<PrincipalInner>>();
+        Ok(Some(AuditContext {
+            principal: principal.map(|it| format!("{}:{}", it.auth_kind, it.principal)),
+            impersonator: principal.and_then(|it| it.impersonator.clone()),
+            request_id: Some(rid),
+            now,
+        }))
+    }
+}
+
+// current values of `cols` (all when empty) of a row, none when auditing is off or the row is missing
+pub(crate) async fn snapshot<B: Backend>(db: &mut DbWriteTxn<'_, B>, table: &str, idcol: &str, id: &str, cols: &[&str]) -> anyhow::Result<Option<Map<String, Value>>> {
+    snapshot_by(db, table, idcol, Args::<B>::new().bind(id), cols).await
+}
+
+// snapshot with the id bound as the type of its column (see TableRow::bind_id), so the lookup uses the key
+pub(crate) async fn snapshot_by<B: Backend>(db: &mut DbWriteTxn<'_, B>, table: &str, idcol: &str, id: Args<'_, B>, cols: &[&str]) -> anyhow::Result<Option<Map<String, Value>>> {
+    if db.audit().is_none() { return Ok(None) }
+    let what = if cols.is_empty() { String::from("*") } else { cols.join(", ") };
+    let row = sqlx::query_with(&format!("SELECT {what} FROM {table} WHERE {idcol} = $1"), id)
+        .fetch_optional(db.conn())
+        .await?;
+    Ok(row.as_ref().map(B::row_to_json))
+}
+
+/// Writes the audit entry for a change, for updates only the fields that changed are kept.
+/// Does nothing when the transaction has no AuditContext.
+pub async fn record_change<B: Backend>(db: &mut DbWriteTxn<'_, B>, table: &str, action: &str, row_id: Option<&str>,
+                           old: Option<Map<String, Value>>, new: Option<Map<String, Value>>) -> anyhow::Result<()> {
+    let Some(ctx) = db.audit().cloned() else { return Ok(()) };
+    let (old, new) = match (old, new) {
+        (Some(mut old), Some(mut new)) => {
+            old.retain(|k, v| new.get(k) != Some(v));
+            new.retain(|k, _| old.contains_key(k));
+            // nothing changed, nothing to audit
+            if new.is_empty() { return Ok(()) }
+            (Some(old), Some(new))
+        },
+        other => other,
+    };
+    sqlx::query_with("INSERT INTO row_audit (table_name, row_id, action, old_values, new_values, principal, impersonator, request_id, created) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)", Args::<B>::new()
+            .bind(table)
+            .bind(row_id)
+            .bind(action)
+            .bind(old.map(|it| Value::Object(it).to_string()))
+            .bind(new.map(|it| Value::Object(it).to_string()))
+            .bind(ctx.principal)
+            .bind(ctx.impersonator)
+            .bind(ctx.request_id)
+            .bind(ctx.now))
+        .execute(db.conn())
+        .await?;
+    Ok(())
+}
+
+// id as text from a snapshot, for row_id
+pub(crate) fn id_of(values: &Option<Map<String, Value>>, idcol: &str) -> Option<String> {
+    match values.as_ref()?.get(idcol)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Null => None,
+        other => Some(other.to_string()),
+    }
+}
@@ -1,6 +1,7 @@
 use std::cell::{Cell};
 use std::fmt::Debug;
 use std::future::{Ready, ready};
+use std::marker::PhantomData;
 
 use std::rc::Rc;
 
@@ -8,8 +9,11 @@ use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceRes
 use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, web};
 use anyhow::anyhow;
 use futures_util::future::LocalBoxFuture;
+use crate::cfg::Cfg;
 use crate::db::{DbMain, DbWriteTxn};
+use crate::device::{SESSION_KIND_DEVICE, verify_device};
 use crate::errors::{AnyHandlerError, ApiError};
+use crate::impersonate::{record_impersonation, AdminSession};
 use crate::interface::{AppContainer, Session};
 use crate::oidc::{SESSION_KIND_OIDC};
 use crate::utils::Instant;
@@ -26,20 +30,33 @@ pub struct PrincipalInner {
     pub auth_kind: String, // use e.g. SESSION_KIND_OIDC
     pub principal: String, // email, device_id, ...
     pub parent: Option<String>, // optional additional data such as shop_id
+    pub impersonator: Option<String>, // admin email when acting as the principal, see impersonate.rs
+}
+
+impl PrincipalInner {
+    pub fn is_impersonated(&self) -> bool {
+        self.impersonator.is_some()
+    }
 }
 
 pub struct PrincipalTaken {
     taken: Cell<bool>
 }
 
-pub struct AuthMidFactory<AC> {
-    db: DbMain,
+impl PrincipalTaken {
+    pub fn set_taken(&self) {
+        self.taken.set(true);
+    }
+}
+
+pub struct AuthMidFactory<AC: AppContainer> {
+    db: DbMain<AC::B>,
     required: bool,
     phantom_ac: std::marker::PhantomData<AC>,
 }
 
 impl<AC: AppContainer> AuthMidFactory<AC> {
-    pub fn new(db: DbMain, required: bool) -> Self {
+    pub fn new(db: DbMain<AC::B>, required: bool) -> Self {
         Self {
             db,
             required,
@@ -73,7 +90,7 @@ impl <S, B, AC>Transform<S, ServiceRequest> for AuthMidFactory<AC>
 
 pub struct AuthMiddleware<S, AC: AppContainer> {
     service: Rc<S>,
-    db: DbMain,
+    db: DbMain<AC::B>,
     required: bool,
     phantom_ac: std::marker::PhantomData<AC>
 }
@@ -93,25 +110,42 @@ impl<S, B, AC> Service<ServiceRequest> for AuthMiddleware<S, AC>
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
 
-        async fn use_session<AC: AppContainer>(txn: &mut DbWriteTxn<'_>, sess_found: &AC::S, now: Instant) -> anyhow::Result<()> {
+        async fn use_session<AC: AppContainer>(txn: &mut DbWriteTxn<'_, AC::B>, sess_found: &AC::S, now: Instant) -> anyhow::Result<()> {
             if sess_found.expires() < now {
-                AC::S::delete(txn, &sess_found.code()).await?;
+                AC::S::delete(txn, sess_found.code()).await?;
                 return Err(ApiError::Expired.into())
+            } else if sess_found.impersonator().is_some() {
+                // impersonation sessions are never extended
             } else {
-                AC::S::extend(txn, &sess_found.code(), now + AC::S::lifetime(&sess_found.kind())).await?;
+                AC::S::extend(txn, sess_found.code(), now + AC::S::lifetime(&sess_found.kind())).await?;
             }
             Ok(())
         }
 
-        // goes from parsed only auth info to verified principal
-        async fn verify_auth<AC: AppContainer>(db: &DbMain, objs: web::Data<AC>, auth: Option<Authentication>) -> anyhow::Result<Option<Rc<PrincipalInner>>> {
+        // goes from parsed only auth info to verified principal, with the admin's own session when impersonating
+        async fn verify_auth<AC: AppContainer>(db: &DbMain<AC::B>, objs: web::Data<AC>, auth: Option<Authentication>, method: String, path: String, route: String) -> anyhow::Result<Option<(Rc<PrincipalInner>, Option<AdminSession>)>> {
             if let Some(auth_it) = auth {
+                if auth_it.kind == SESSION_KIND_DEVICE {
+                    // devices have no session, the token is checked directly
+                    let mut txn = db.newtx_read_for(&route).await?;
+                    let principal = verify_device(&mut txn, &auth_it).await?;
+                    txn.commit().await?;
+                    return Ok(Some((Rc::new(principal), None)))
+                }
+
                 // general case
-                let mut txn = db.newtx_write().await?;
+                let mut txn = db.newtx_write_for(&route).await?;
                 let sess = AC::S::find_session(&mut txn, objs.clone(), &auth_it).await?;
-                use_session::<AC>(&mut txn, &sess, objs.utcnow()).await?;
-                let _ = txn.commit().await?;
-                Ok(Some(Rc::new(sess.as_principal()?)))
+                let now = objs.utcnow();
+                use_session::<AC>(&mut txn, &sess, now).await?;
+                let mut principal = sess.as_principal()?;
+                principal.impersonator = sess.impersonator().map(String::from);
+                if let Some(ref admin) = principal.impersonator {
+                    record_impersonation(&mut txn, admin, &principal, &method, &path, now).await?;
+                }
+                txn.commit().await?;
+                let admin_session = sess.admin_session().map(|it| AdminSession(it.into()));
+                Ok(Some((Rc::new(principal), admin_session)))
 
             } else {
                 Ok(None)
@@ -123,16 +157,22 @@ impl<S, B, AC> Service<ServiceRequest> for AuthMiddleware<S, AC>
         let required = self.required;
 
         let auth = AC::read_authentication(&req);
+        let method = req.method().to_string();
+        let path = String::from(req.path());
+        let route = req.match_pattern().unwrap_or_else(|| String::from("unmatched"));
 
         Box::pin(async move {
             let objs = AC::from_request(&req)
                 .ok_or(AnyHandlerError::from(ApiError::InvalidState("objs missing".into())))?;
-            let principal = verify_auth(&db, objs.clone(),  auth)
+            let principal = verify_auth(&db, objs.clone(), auth, method, path, route)
                 .await
                 .map_err(AnyHandlerError::from)?;
-            log::debug!("Verified principal {principal:?}");
-            if let Some(principal_it) = principal {
+            log::debug!("Verified principal {:?}", principal.as_ref().map(|it| &it.0));
+            if let Some((principal_it, admin_session)) = principal {
                 req.extensions_mut().insert(principal_it);
+                if let Some(it) = admin_session {
+                    req.extensions_mut().insert(it);
+                }
                 req.extensions_mut().insert(PrincipalTaken { taken: Cell::new(false) });
 
             } else if required {
@@ -163,7 +203,7 @@ pub fn parse_header(parts: Vec<&str>) -> Option<(String, String)> {
 pub fn principal_from_request(req: &HttpRequest, auth_kind: &str) -> Result<Rc<PrincipalInner>, Error> {
     let exts = req.extensions();
     let principal = exts.get::<Rc<PrincipalInner>>()
-        .map_or(Err(ApiError::AuthError1("principal.missing".into()).into()), |it|
+        .map_or(Err(ApiError::AuthError1("principal.missing".into())), |it|
             if it.auth_kind != auth_kind { Err(ApiError::AuthError1(format!("principal.kind.mismatch:a:{}/e:{}", it.auth_kind, auth_kind))) }
             else { Ok(it.clone()) }
         )
@@ -171,11 +211,31 @@ pub fn principal_from_request(req: &HttpRequest, auth_kind: &str) -> Result<Rc<P
         .map_err(Error::from)?;
     let ptaken = exts.get::<PrincipalTaken>();
     if let Some(ptaken_it) = ptaken {
-        ptaken_it.taken.set(true);
+        ptaken_it.set_taken();
     }
     Ok(principal)
 }
 
+// any kind of principal, for handlers that serve all of them (e.g. impersonate_stop)
+#[derive(Clone)]
+pub struct PrincipalAny(pub Rc<PrincipalInner>);
+
+impl FromRequest for PrincipalAny {
+    type Error = Error;
+    type Future = Ready<Result<PrincipalAny, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let exts = req.extensions();
+        let principal = exts.get::<Rc<PrincipalInner>>()
+            .cloned()
+            .ok_or_else(|| Error::from(AnyHandlerError::from(ApiError::AuthError1("principal.missing".into()))));
+        if let (Ok(_), Some(ptaken)) = (&principal, exts.get::<PrincipalTaken>()) {
+            ptaken.set_taken();
+        }
+        ready(principal.map(PrincipalAny))
+    }
+}
+
 // todo? I guess previously I worried about too much copying but that's really irrelevant
 #[derive(Clone)]
 pub struct PrincipalOidc {
@@ -202,3 +262,46 @@ impl FromRequest for PrincipalOidc {
         })
     }
 }
+
+// PrincipalOidc whose email is in ServerSettings::oidc_admins, Unauthorized for other oidc users and for
+// impersonation sessions, an admin acting as another admin doesn't get their rights
+pub struct PrincipalAdmin<AC> {
+    pub email: String,
+    objs: PhantomData<AC>,
+}
+
+impl<AC: AppContainer + 'static> FromRequest for PrincipalAdmin<AC> {
+    type Error = Error;
+    type Future = Ready<Result<PrincipalAdmin<AC>, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(principal_from_request(req, SESSION_KIND_OIDC).and_then(|principal| {
+            let objs = req.app_data::<web::Data<AC>>()
+                .ok_or(AnyHandlerError::from(ApiError::InvalidState("objs missing".into())))?;
+            if principal.is_impersonated() || !objs.cfg().server().oidc_admins.contains(&principal.principal) {
+                return Err(AnyHandlerError::from(ApiError::Unauthorized).into())
+            }
+            Ok(PrincipalAdmin { email: principal.principal.clone(), objs: PhantomData })
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct PrincipalDevice {
+    pub device_id: String,
+    pub parent: Option<String>,
+}
+
+impl FromRequest for PrincipalDevice {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<PrincipalDevice, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let principal = principal_from_request(req, SESSION_KIND_DEVICE);
+
+        Box::pin(async move {
+            let principal = principal?;
+            Ok(PrincipalDevice { device_id: principal.principal.clone(), parent: principal.parent.clone() })
+        })
+    }
+}
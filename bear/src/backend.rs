@@ -0,0 +1,197 @@
+use futures_util::future::BoxFuture;
+use serde_json::{Map, Value};
+use sqlx::database::HasArguments;
+use sqlx::error::DatabaseError;
+use sqlx::{Column, ColumnIndex, Database, Executor, IntoArguments, Row, TypeInfo, ValueRef};
+use crate::cfg::DbSettings;
+
+// The database backends are features that can be enabled together, `sqlite` (default) and `postgres`. DbMain,
+// the transactions and everything working on them are generic over the Backend, an app picks one with
+// AppContainer::B and DbMain<B>. Sqlite is the default for the type parameters when bear is built with it, so
+// sqlite apps can leave it out. Without the sqlite feature there is no default, which keeps the features
+// additive: turning one on never changes what an app that names its backend gets.
+//
+// What differs between the two:
+// - sqlite has one writer connection and readers opened query_only, postgres has pools of writers and
+//   readers, the readers' transactions are READ ONLY
+// - postgres compares types strictly, Instant and i64 columns must be BIGINT and ids passed as &str need
+//   TEXT id columns
+// - backup, maintenance and DbSettings pragmas are sqlite only, postgres has its own tooling for those
+// - the *_SCHEMA constants come in a version per backend, see BackendSql
+//
+// Queries use `$1, $2` placeholders, which sqlx maps on both.
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "postgres")]
+mod postgres;
+
+/// Marker for bear on sqlite, `DbMain<Sqlite>`. Only a Backend with the `sqlite` feature.
+#[derive(Debug, Clone, Copy)]
+pub struct Sqlite;
+
+/// Marker for bear on postgres, `DbMain<Postgres>`. Only a Backend with the `postgres` feature.
+#[derive(Debug, Clone, Copy)]
+pub struct Postgres;
+
+pub type DbConnection<B> = <<B as Backend>::Db as Database>::Connection;
+pub type DbRow<B> = <<B as Backend>::Db as Database>::Row;
+pub type DbPool<B> = sqlx::Pool<<B as Backend>::Db>;
+pub type DbArguments<'q, B> = <<B as Backend>::Db as HasArguments<'q>>::Arguments;
+pub type DbQueryResult<B> = <<B as Backend>::Db as Database>::QueryResult;
+
+pub trait Backend: Send + Sync + Sized + 'static {
+    type Db: Database;
+    // what a connection is as an executor, `&mut Connection`
+    type Exec<'c>: Executor<'c, Database = Self::Db>;
+
+    const NAME: &'static str;
+    // LIMIT for "no limit", sqlite needs one before OFFSET
+    const NO_LIMIT: &'static str;
+
+    fn exec(conn: &mut DbConnection<Self>) -> Self::Exec<'_>;
+
+    // picks the version of what is written per backend, see BackendSql
+    fn by_backend(sqlite: &'static str, postgres: &'static str) -> &'static str;
+
+    // lock contention worth a retry, see db::is_busy_error
+    fn is_busy(dbe: &dyn DatabaseError) -> bool;
+
+    // the reader and writer pools for db_init_with
+    fn connect<'a>(url: &'a str, migrator: &'a sqlx::migrate::Migrator, settings: &'a DbSettings) -> BoxFuture<'a, anyhow::Result<(DbPool<Self>, DbPool<Self>)>>;
+
+    // the arguments for a query shorter lived than the values, sqlx ties both to one lifetime
+    fn narrow<'a: 'q, 'q>(args: DbArguments<'a, Self>) -> DbArguments<'q, Self>;
+
+    // binds a value in generic code, see Param
+    fn add<'q, V: Param<'q>>(args: &mut DbArguments<'q, Self>, v: V);
+
+    // reads a column in generic code, see Field
+    fn get<V: Field>(row: &DbRow<Self>, col: &str) -> Result<V, sqlx::Error>;
+
+    fn rows_affected(res: &DbQueryResult<Self>) -> u64;
+
+    // the row as json for the audit log
+    fn row_to_json(row: &DbRow<Self>) -> Map<String, Value>;
+}
+
+/// SQL written once per backend, e.g. the *_SCHEMA constants to add to app migrations:
+/// `ROW_AUDIT_SCHEMA.on::<Sqlite>()`.
+#[derive(Debug, Clone, Copy)]
+pub struct BackendSql {
+    pub sqlite: &'static str,
+    pub postgres: &'static str,
+}
+
+impl BackendSql {
+    pub fn on<B: Backend>(&self) -> &'static str {
+        B::by_backend(self.sqlite, self.postgres)
+    }
+}
+
+// Generic code can't name what sqlx encodes and decodes on B::Db, so the values bear binds and reads itself
+// (ids, timestamps, text) go through Backend::add and Backend::get, bounded by these. They hold for whatever
+// sqlx supports on every backend bear is built with. What apps bind (Assignments, Cond) is bounded by B::Db
+// instead, so an app's values only need to work on its own backend.
+
+/// A value Backend::add binds.
+pub trait Param<'q>: SqliteParam<'q> + PostgresParam<'q> + Send + 'q {}
+
+impl<'q, T: SqliteParam<'q> + PostgresParam<'q> + Send + 'q> Param<'q> for T {}
+
+/// A column Backend::get reads.
+pub trait Field: SqliteField + PostgresField {}
+
+impl<T: SqliteField + PostgresField> Field for T {}
+
+#[cfg(feature = "sqlite")]
+#[doc(hidden)]
+pub trait SqliteParam<'q>: sqlx::Encode<'q, sqlx::Sqlite> + sqlx::Type<sqlx::Sqlite> {}
+#[cfg(feature = "sqlite")]
+impl<'q, T: sqlx::Encode<'q, sqlx::Sqlite> + sqlx::Type<sqlx::Sqlite>> SqliteParam<'q> for T {}
+#[cfg(not(feature = "sqlite"))]
+#[doc(hidden)]
+pub trait SqliteParam<'q> {}
+#[cfg(not(feature = "sqlite"))]
+impl<'q, T> SqliteParam<'q> for T {}
+
+#[cfg(feature = "postgres")]
+#[doc(hidden)]
+pub trait PostgresParam<'q>: sqlx::Encode<'q, sqlx::Postgres> + sqlx::Type<sqlx::Postgres> {}
+#[cfg(feature = "postgres")]
+impl<'q, T: sqlx::Encode<'q, sqlx::Postgres> + sqlx::Type<sqlx::Postgres>> PostgresParam<'q> for T {}
+#[cfg(not(feature = "postgres"))]
+#[doc(hidden)]
+pub trait PostgresParam<'q> {}
+#[cfg(not(feature = "postgres"))]
+impl<'q, T> PostgresParam<'q> for T {}
+
+#[cfg(feature = "sqlite")]
+#[doc(hidden)]
+pub trait SqliteField: for<'r> sqlx::Decode<'r, sqlx::Sqlite> + sqlx::Type<sqlx::Sqlite> {}
+#[cfg(feature = "sqlite")]
+impl<T: for<'r> sqlx::Decode<'r, sqlx::Sqlite> + sqlx::Type<sqlx::Sqlite>> SqliteField for T {}
+#[cfg(not(feature = "sqlite"))]
+#[doc(hidden)]
+pub trait SqliteField {}
+#[cfg(not(feature = "sqlite"))]
+impl<T> SqliteField for T {}
+
+#[cfg(feature = "postgres")]
+#[doc(hidden)]
+pub trait PostgresField: for<'r> sqlx::Decode<'r, sqlx::Postgres> + sqlx::Type<sqlx::Postgres> {}
+#[cfg(feature = "postgres")]
+impl<T: for<'r> sqlx::Decode<'r, sqlx::Postgres> + sqlx::Type<sqlx::Postgres>> PostgresField for T {}
+#[cfg(not(feature = "postgres"))]
+#[doc(hidden)]
+pub trait PostgresField {}
+#[cfg(not(feature = "postgres"))]
+impl<T> PostgresField for T {}
+
+/// Arguments for sqlx::query_with in code generic over the backend:
+/// `sqlx::query_with(&sql, Args::<B>::new().bind(id).bind(now)).execute(db.conn())`
+pub struct Args<'q, B: Backend>(DbArguments<'q, B>);
+
+impl<'q, B: Backend> Args<'q, B> {
+    pub fn new() -> Self {
+        Args(DbArguments::<'q, B>::default())
+    }
+
+    pub fn bind<V: Param<'q>>(mut self, v: V) -> Self {
+        B::add(&mut self.0, v);
+        self
+    }
+
+    // arguments built with Arguments::add, e.g. by TableRow or Assignments
+    pub fn from_arguments(args: DbArguments<'q, B>) -> Self {
+        Args(args)
+    }
+}
+
+impl<'q, B: Backend> Default for Args<'q, B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a: 'q, 'q, B: Backend> IntoArguments<'q, B::Db> for Args<'a, B> {
+    fn into_arguments(self) -> DbArguments<'q, B> {
+        B::narrow(self.0)
+    }
+}
+
+// generic row to json, the backends map the column types
+fn row_to_json<R: Row>(row: &R, column_to_json: fn(&R, usize, &str) -> Value) -> Map<String, Value>
+    where usize: ColumnIndex<R>
+{
+    let mut res = Map::new();
+    for (i, col) in row.columns().iter().enumerate() {
+        let value = match row.try_get_raw(i) {
+            Ok(raw) if raw.is_null() => Value::Null,
+            Ok(raw) => column_to_json(row, i, raw.type_info().name()),
+            Err(_) => Value::Null,
+        };
+        res.insert(col.name().to_string(), value);
+    }
+    res
+}
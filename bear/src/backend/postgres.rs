@@ -0,0 +1,94 @@
+use std::str::FromStr;
+use futures_util::future::BoxFuture;
+use serde_json::{Map, Value};
+use sqlx::error::DatabaseError;
+use sqlx::postgres::{PgArguments, PgConnectOptions, PgPoolOptions, PgQueryResult, PgRow};
+use sqlx::{Arguments, PgConnection, PgPool, Row};
+use crate::cfg::DbSettings;
+use super::{Backend, Field, Param, Postgres};
+
+// Postgres has pools of writers and readers, the readers' transactions are READ ONLY. It compares types
+// strictly: Instant and i64 columns must be BIGINT and ids passed as &str need TEXT id columns.
+
+impl Backend for Postgres {
+    type Db = sqlx::Postgres;
+    type Exec<'c> = &'c mut PgConnection;
+
+    const NAME: &'static str = "postgres";
+    const NO_LIMIT: &'static str = "ALL";
+
+    fn exec(conn: &mut PgConnection) -> &mut PgConnection {
+        conn
+    }
+
+    fn by_backend(_sqlite: &'static str, postgres: &'static str) -> &'static str {
+        postgres
+    }
+
+    // serialization_failure, deadlock_detected and lock_not_available, all worth a retry
+    fn is_busy(dbe: &dyn DatabaseError) -> bool {
+        dbe.code().is_some_and(|code| matches!(code.as_ref(), "40001" | "40P01" | "55P03"))
+    }
+
+    fn connect<'a>(url: &'a str, migrator: &'a sqlx::migrate::Migrator, settings: &'a DbSettings) -> BoxFuture<'a, anyhow::Result<(PgPool, PgPool)>> {
+        Box::pin(connect(url, migrator, settings))
+    }
+
+    fn narrow<'a: 'q, 'q>(args: PgArguments) -> PgArguments {
+        args
+    }
+
+    fn add<'q, V: Param<'q>>(args: &mut PgArguments, v: V) {
+        args.add(v)
+    }
+
+    fn get<V: Field>(row: &PgRow, col: &str) -> Result<V, sqlx::Error> {
+        row.try_get(col)
+    }
+
+    fn rows_affected(res: &PgQueryResult) -> u64 {
+        res.rows_affected()
+    }
+
+    fn row_to_json(row: &PgRow) -> Map<String, Value> {
+        super::row_to_json(row, column_to_json)
+    }
+}
+
+// one column as json for the audit log, by the type the database reports
+fn column_to_json(row: &PgRow, i: usize, type_name: &str) -> Value {
+    use hex::ToHex;
+    // postgres decodes strictly by type, anything not listed is read as text or left out
+    match type_name {
+        "INT2" => row.try_get::<i16, _>(i).map(Value::from).unwrap_or(Value::Null),
+        "INT4" => row.try_get::<i32, _>(i).map(Value::from).unwrap_or(Value::Null),
+        "INT8" => row.try_get::<i64, _>(i).map(Value::from).unwrap_or(Value::Null),
+        "FLOAT4" => row.try_get::<f32, _>(i).map(Value::from).unwrap_or(Value::Null),
+        "FLOAT8" => row.try_get::<f64, _>(i).map(Value::from).unwrap_or(Value::Null),
+        "BOOL" => row.try_get::<bool, _>(i).map(Value::from).unwrap_or(Value::Null),
+        "BYTEA" => row.try_get::<Vec<u8>, _>(i).map(|it| Value::from(it.encode_hex::<String>())).unwrap_or(Value::Null),
+        _ => row.try_get::<String, _>(i).map(Value::from).unwrap_or(Value::Null),
+    }
+}
+
+// Postgres handles concurrent writers itself, so the writer pool
+// can be larger than one. Readers are READ ONLY sessions, the counterpart of query_only. A lock not granted
+// within busy_timeout_ms fails with lock_not_available, which is_busy_error lets the middleware retry.
+async fn connect(url: &str, migrator: &sqlx::migrate::Migrator, settings: &DbSettings) -> anyhow::Result<(PgPool, PgPool)> {
+    let opts = PgConnectOptions::from_str(url)?
+        .statement_cache_capacity(settings.statement_cache)
+        .options([("lock_timeout", format!("{}ms", settings.busy_timeout_ms))]);
+
+    let wpool = PgPoolOptions::new()
+        .max_connections(settings.writer_pool_size)
+        .connect_with(opts.clone()).await?;
+
+    migrator.run(&wpool).await?;
+
+    let rpool = PgPoolOptions::new()
+        .max_connections(settings.reader_pool_size)
+        .connect_with(opts.options([("default_transaction_read_only", "on")])).await?;
+
+    log::info!("Postgres pools: writers={}, readers={}", settings.writer_pool_size, settings.reader_pool_size);
+    Ok((rpool, wpool))
+}
@@ -0,0 +1,127 @@
+use std::str::FromStr;
+use std::time::Duration;
+use futures_util::future::BoxFuture;
+use serde_json::{Map, Value};
+use sqlx::error::DatabaseError;
+use sqlx::sqlite::{SqliteArguments, SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteQueryResult, SqliteRow, SqliteSynchronous};
+use sqlx::{Arguments, Row, SqliteConnection, SqlitePool};
+use crate::cfg::DbSettings;
+use crate::errors::ApiError;
+use super::{Backend, Field, Param, Sqlite};
+
+// SQLite has one writer connection and readers opened query_only. The DbSettings pragmas only apply here,
+// as do backup and maintenance, which work on the database file.
+
+impl Backend for Sqlite {
+    type Db = sqlx::Sqlite;
+    type Exec<'c> = &'c mut SqliteConnection;
+
+    const NAME: &'static str = "sqlite";
+    const NO_LIMIT: &'static str = "-1";
+
+    fn exec(conn: &mut SqliteConnection) -> &mut SqliteConnection {
+        conn
+    }
+
+    fn by_backend(sqlite: &'static str, _postgres: &'static str) -> &'static str {
+        sqlite
+    }
+
+    // SQLITE_BUSY and SQLITE_LOCKED including their extended codes
+    fn is_busy(dbe: &dyn DatabaseError) -> bool {
+        dbe.code()
+            .and_then(|code| code.parse::<i32>().ok())
+            .is_some_and(|code| matches!(code & 0xff, 5 | 6))
+    }
+
+    fn connect<'a>(url: &'a str, migrator: &'a sqlx::migrate::Migrator, settings: &'a DbSettings) -> BoxFuture<'a, anyhow::Result<(SqlitePool, SqlitePool)>> {
+        Box::pin(connect(url, migrator, settings))
+    }
+
+    fn narrow<'a: 'q, 'q>(args: SqliteArguments<'a>) -> SqliteArguments<'q> {
+        args
+    }
+
+    fn add<'q, V: Param<'q>>(args: &mut SqliteArguments<'q>, v: V) {
+        args.add(v)
+    }
+
+    fn get<V: Field>(row: &SqliteRow, col: &str) -> Result<V, sqlx::Error> {
+        row.try_get(col)
+    }
+
+    fn rows_affected(res: &SqliteQueryResult) -> u64 {
+        res.rows_affected()
+    }
+
+    fn row_to_json(row: &SqliteRow) -> Map<String, Value> {
+        super::row_to_json(row, column_to_json)
+    }
+}
+
+// one column as json for the audit log, by the type the database reports
+fn column_to_json(row: &SqliteRow, i: usize, type_name: &str) -> Value {
+    use hex::ToHex;
+    // sqlite only has these storage classes
+    match type_name {
+        "INTEGER" | "BOOLEAN" => row.try_get::<i64, _>(i).map(Value::from).unwrap_or(Value::Null),
+        "REAL" => row.try_get::<f64, _>(i).map(Value::from).unwrap_or(Value::Null),
+        "BLOB" => row.try_get::<Vec<u8>, _>(i).map(|it| Value::from(it.encode_hex::<String>())).unwrap_or(Value::Null),
+        _ => row.try_get::<String, _>(i).map(Value::from).unwrap_or(Value::Null),
+    }
+}
+
+async fn connect(url: &str, migrator: &sqlx::migrate::Migrator, settings: &DbSettings) -> anyhow::Result<(SqlitePool, SqlitePool)> {
+    if settings.writer_pool_size != 1 {
+        return Err(ApiError::InvalidState(String::from("db.writer_pool_size.invalid")).into())
+    }
+    let mut opts = SqliteConnectOptions::from_str(url)?
+        .busy_timeout(Duration::from_millis(settings.busy_timeout_ms))
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::from_str(&settings.synchronous)?)
+        .foreign_keys(settings.foreign_keys)
+        .statement_cache_capacity(settings.statement_cache)
+        .pragma("mmap_size", settings.mmap_size.to_string())
+        .create_if_missing(true);
+    if let Some(cache_size) = settings.cache_size {
+        opts = opts.pragma("cache_size", cache_size.to_string());
+    }
+
+    let wpool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(opts.clone()).await?;
+
+    migrator.run(&wpool).await?;
+
+    let rpool = SqlitePoolOptions::new()
+        .max_connections(settings.reader_pool_size)
+        .connect_with(opts.pragma("query_only", "ON")).await?;
+
+    log_effective_settings(&wpool, settings.reader_pool_size).await?;
+
+    // NOTES
+    // We are using WAL mode (which is default). In this mode there can be many readers with one writer at a time.
+    // sqlx is planning to support W-only and R-only pools where the W-only pool has size 1. Unfortunately,
+    // this is not available yet. This may be more efficient than having a timeout.
+    // Sqlx has a separate thread pool that queries are dispatched to (apparently). So even running a single thread
+    // code will log queries from multiple threads / (connections??)
+    // The limit on one writer at a time applies to a whole transaction actually.
+
+    Ok((rpool, wpool))
+}
+
+// what sqlite actually uses, pragmas can be silently ignored (e.g. mmap_size when compiled without mmap)
+async fn log_effective_settings(pool: &SqlitePool, readers: u32) -> anyhow::Result<()> {
+    let mut conn = pool.acquire().await?;
+    let mut values = vec![];
+    for pragma in ["journal_mode", "synchronous", "busy_timeout", "cache_size", "mmap_size", "foreign_keys"] {
+        let row = sqlx::query(&format!("PRAGMA {pragma}"))
+            .fetch_one(&mut *conn)
+            .await?;
+        let v = row.try_get::<i64, _>(0).map(|it| it.to_string())
+            .or_else(|_| row.try_get::<String, _>(0))?;
+        values.push(format!("{pragma}={v}"));
+    }
+    log::info!("SQLite settings: {}, readers={readers}", values.join(", "));
+    Ok(())
+}
@@ -0,0 +1,214 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use actix_web::web;
+use metrics::{counter, histogram};
+use serde::{Deserialize, Serialize};
+use sqlx::Connection;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection};
+use crate::authmw::PrincipalAdmin;
+use crate::db::DbMain;
+use crate::errors::{AnyHandlerError, ApiError};
+use crate::interface::AppContainer;
+use crate::utils::Instant;
+
+// Online backups. VACUUM INTO writes a consistent snapshot while readers and the writer keep going
+// (it only needs a read transaction in WAL mode). The copy is integrity checked, optionally gzipped,
+// and only the newest `keep` backups are left in the directory.
+//
+// restore_backup is for disaster recovery drills and the real thing: it writes a database file that
+// the app can then be started on. It must not be pointed at the file of a running DbMain.
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BackupSettings {
+    pub dir: PathBuf,
+    pub keep: usize,
+    pub compress: bool,
+    // file names are <prefix>-<utc timestamp>.db[.gz]
+    pub prefix: String,
+}
+
+impl Default for BackupSettings {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("backups"),
+            keep: 7,
+            compress: true,
+            prefix: String::from("backup"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ts_rs::TS)]
+pub struct BackupInfo {
+    pub file: String,
+    pub size: u64,
+}
+
+pub async fn backup(db: &DbMain, settings: &BackupSettings, now: Instant) -> anyhow::Result<BackupInfo> {
+    let started = std::time::Instant::now();
+    let res = backup_inner(db, settings, now).await;
+    match res {
+        Ok(ref info) => {
+            histogram!("db_backup_seconds", started.elapsed().as_secs_f64());
+            log::info!("Backup written to {} ({} bytes)", info.file, info.size);
+        },
+        Err(ref e) => {
+            counter!("db_backup_failures", 1);
+            log::error!("Backup failed: {e:?}");
+        }
+    }
+    res
+}
+
+async fn backup_inner(db: &DbMain, settings: &BackupSettings, now: Instant) -> anyhow::Result<BackupInfo> {
+    tokio::fs::create_dir_all(&settings.dir).await?;
+    let stamp = chrono::DateTime::from_timestamp(now, 0)
+        .ok_or(ApiError::InvalidInput)?
+        .format("%Y%m%d-%H%M%S");
+    let target = settings.dir.join(format!("{}-{stamp}.db", settings.prefix));
+    // VACUUM INTO refuses to overwrite
+    if tokio::fs::try_exists(&target).await? {
+        return Err(ApiError::InvalidState(format!("backup.exists:{}", target.display())).into())
+    }
+
+    let mut conn = db.connect_detached().await?;
+    let res = sqlx::query("VACUUM INTO ?")
+        .bind(target.to_string_lossy().as_ref())
+        .execute(&mut conn)
+        .await;
+    conn.close().await?;
+    res?;
+
+    if let Err(e) = verify_backup(&target).await {
+        let _ = tokio::fs::remove_file(&target).await;
+        return Err(e)
+    }
+
+    let file = if settings.compress {
+        let gz = PathBuf::from(format!("{}.gz", target.display()));
+        let (from, to) = (target.clone(), gz.clone());
+        let zipped = match tokio::task::spawn_blocking(move || gzip(&from, &to)).await {
+            Ok(it) => it,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = zipped {
+            // neither the uncompressed copy nor a partial .gz should be picked up by list_backups
+            let _ = tokio::fs::remove_file(&gz).await;
+            let _ = tokio::fs::remove_file(&target).await;
+            return Err(e)
+        }
+        tokio::fs::remove_file(&target).await?;
+        gz
+    } else {
+        target
+    };
+
+    rotate(settings).await?;
+    let size = tokio::fs::metadata(&file).await?.len();
+    Ok(BackupInfo { file: file.to_string_lossy().into_owned(), size })
+}
+
+fn gzip(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let mut input = BufReader::new(File::open(from)?);
+    let mut output = flate2::write::GzEncoder::new(BufWriter::new(File::create(to)?), flate2::Compression::default());
+    std::io::copy(&mut input, &mut output)?;
+    output.finish()?;
+    Ok(())
+}
+
+fn gunzip(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let mut input = flate2::read::GzDecoder::new(BufReader::new(File::open(from)?));
+    let mut output = BufWriter::new(File::create(to)?);
+    std::io::copy(&mut input, &mut output)?;
+    Ok(())
+}
+
+/// Runs PRAGMA integrity_check on an uncompressed database file.
+pub async fn verify_backup(path: &Path) -> anyhow::Result<()> {
+    let opts = SqliteConnectOptions::from_str(&format!("sqlite:{}", path.display()))?
+        .read_only(true);
+    let mut conn = SqliteConnection::connect_with(&opts).await?;
+    let res: Vec<String> = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_all(&mut conn)
+        .await?;
+    conn.close().await?;
+    if res != ["ok"] {
+        log::error!("Integrity check of {} failed: {}", path.display(), res.join("; "));
+        return Err(ApiError::InvalidState(format!("backup.corrupt:{}", path.display())).into())
+    }
+    Ok(())
+}
+
+// newest first
+pub async fn list_backups(settings: &BackupSettings) -> anyhow::Result<Vec<PathBuf>> {
+    let mut res = vec![];
+    let mut entries = match tokio::fs::read_dir(&settings.dir).await {
+        Ok(it) => it,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(res),
+        Err(e) => return Err(e.into()),
+    };
+    let prefix = format!("{}-", settings.prefix);
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(&prefix) && (name.ends_with(".db") || name.ends_with(".db.gz")) {
+            res.push(entry.path());
+        }
+    }
+    // the timestamp in the name sorts
+    res.sort();
+    res.reverse();
+    Ok(res)
+}
+
+async fn rotate(settings: &BackupSettings) -> anyhow::Result<()> {
+    for old in list_backups(settings).await?.iter().skip(settings.keep.max(1)) {
+        log::info!("Removing old backup {}", old.display());
+        tokio::fs::remove_file(old).await?;
+    }
+    Ok(())
+}
+
+/// Writes `backup` (.db or .db.gz) to `target` after checking its integrity. Leftover -wal and -shm
+/// files of the target are removed, they belong to the old database.
+pub async fn restore_backup(backup: &Path, target: &Path) -> anyhow::Result<()> {
+    let tmp = PathBuf::from(format!("{}.restoring", target.display()));
+    if let Err(e) = restore_to(backup, &tmp).await {
+        // a partial copy must not be mistaken for a database
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(e)
+    }
+    for suffix in ["-wal", "-shm"] {
+        let _ = tokio::fs::remove_file(format!("{}{suffix}", target.display())).await;
+    }
+    if let Err(e) = tokio::fs::rename(&tmp, target).await {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(e.into())
+    }
+    log::warn!("Restored {} from {}", target.display(), backup.display());
+    Ok(())
+}
+
+async fn restore_to(backup: &Path, tmp: &Path) -> anyhow::Result<()> {
+    let (from, to) = (backup.to_path_buf(), tmp.to_path_buf());
+    if backup.extension().is_some_and(|it| it == "gz") {
+        tokio::task::spawn_blocking(move || gunzip(&from, &to)).await??;
+    } else {
+        tokio::fs::copy(&from, &to).await?;
+    }
+    verify_backup(tmp).await
+}
+
+// admin only, needs web::Data<BackupSettings>
+pub async fn backup_now<AC>(
+    objs: web::Data<AC>,
+    db: web::Data<DbMain>,
+    settings: web::Data<BackupSettings>,
+    admin: PrincipalAdmin<AC>,
+) -> Result<web::Json<BackupInfo>, AnyHandlerError>
+    where AC: AppContainer + 'static
+{
+    log::warn!("Backup requested by {}", admin.email);
+    Ok(web::Json(backup(&db, &settings, objs.utcnow()).await?))
+}
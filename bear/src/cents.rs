@@ -1,7 +1,7 @@
 use num_traits::ToPrimitive;
 
 pub fn cents_display(amt: i64) -> String {
-    return (amt.to_f32().unwrap() / 100.0).to_string();
+    (amt.to_f32().unwrap() / 100.0).to_string()
 }
 
 pub struct Cents {}
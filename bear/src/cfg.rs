@@ -19,6 +19,49 @@ pub struct ServerSettings {
     pub ssm_prefix: String,
 }
 
+// tuning for db_init_with, embed in the app config as `#[serde(default)] pub db: DbSettings`.
+// postgres only uses busy_timeout_ms (as lock_timeout), the pool sizes and statement_cache.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DbSettings {
+    pub busy_timeout_ms: u64,
+    pub synchronous: String, // off, normal, full or extra
+    pub cache_size: Option<i64>, // negative is KiB, positive pages, sqlite default otherwise
+    pub mmap_size: u64, // bytes, 0 is off
+    pub foreign_keys: bool,
+    pub reader_pool_size: u32,
+    pub writer_pool_size: u32, // sqlite has a single writer, must be 1 there
+    pub statement_cache: usize, // per connection
+}
+
+// what db_init did before DbSettings existed
+impl Default for DbSettings {
+    fn default() -> Self {
+        Self {
+            busy_timeout_ms: 5000,
+            synchronous: String::from("full"),
+            cache_size: None,
+            mmap_size: 0,
+            foreign_keys: true,
+            reader_pool_size: 10,
+            writer_pool_size: 1,
+            statement_cache: 100,
+        }
+    }
+}
+
+impl DbSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let invalid = |field: &str| Err(ApiError::InvalidState(format!("db.{field}.invalid")).into());
+        if !["off", "normal", "full", "extra"].contains(&self.synchronous.to_lowercase().as_str()) { return invalid("synchronous") }
+        if self.busy_timeout_ms > 10 * 60 * 1000 { return invalid("busy_timeout_ms") }
+        if self.reader_pool_size == 0 || self.reader_pool_size > 1000 { return invalid("reader_pool_size") }
+        if self.writer_pool_size == 0 || self.writer_pool_size > 1000 { return invalid("writer_pool_size") }
+        if self.cache_size == Some(0) { return invalid("cache_size") }
+        Ok(())
+    }
+}
+
 pub trait SsmKeyTrait: strum::IntoEnumIterator + Eq + Hash {
     fn key(&self) -> &str;
 }
@@ -48,7 +91,7 @@ pub async fn aws_ssm_client(dev_mode: bool) -> Option<AwsClient> {
         builder = builder.profile_name("earn_backend_dev")
     }
     let shared_config = builder.load().await;
-    if shared_config.region().is_none() { return None };
+    shared_config.region()?;
     let cl = aws::Client::new(&shared_config);
     Some(cl)
 }
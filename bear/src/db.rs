@@ -1,155 +1,667 @@
-use std::str::FromStr;
-use sqlx::{Execute, FromRow, Pool, Sqlite, Transaction};
-use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteRow};
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+use futures_util::future::BoxFuture;
+use metrics::{counter, histogram};
+use crate::audit::{self, AuditContext, AUDIT_DELETE, AUDIT_INSERT, AUDIT_RESTORE, AUDIT_SOFT_DELETE, AUDIT_UPDATE, AUDIT_UPSERT};
+use crate::backend::{Args, Backend, DbArguments, DbConnection, DbPool, DbRow};
+#[cfg(feature = "sqlite")]
+use crate::backend::Sqlite;
+use crate::cfg::DbSettings;
+use crate::errors::ApiError;
+use crate::txnmw::RetryPolicy;
+use crate::utils::Instant;
+use sqlx::{Arguments, FromRow, Transaction};
 
-pub type DbTxn<'a> = Transaction<'a, Sqlite>;
-pub type DbReadTxn<'a> = Transaction<'a, Sqlite>;
-// TODO cant use write txn as read txn
-pub type DbWriteTxn<'a> = Transaction<'a, Sqlite>;
+use crate::table::{placeholder, placeholders};
 
-#[derive(Clone)]
-pub struct DbMain {
-    readers: Pool<Sqlite>,
-    writer: Pool<Sqlite>
+pub use crate::table::{checked_column, TableMetadata, TableRow};
+
+// The types generic over the Backend default to Sqlite when bear is built with it, see backend.rs.
+
+/// Transaction on a reader connection. Those are opened query_only (sqlite) or READ ONLY (postgres) so writes
+/// fail at runtime.
+/// Derefs to the connection, so `&mut **db` works as an executor where the backend is known, generic code
+/// uses `.conn()`.
+pub struct DbReadTxn<'a, #[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend>(Transaction<'a, B::Db>, std::time::Instant);
+
+/// Transaction on the writer connection. Derefs to DbReadTxn so it can be passed wherever reading is
+/// enough, use `.conn()` as the executor. With an AuditContext the generic write helpers log to row_audit.
+pub struct DbWriteTxn<'a, #[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend>(DbReadTxn<'a, B>, Option<AuditContext>);
+
+// anything that can be read from, &mut DbWriteTxn coerces to it
+pub type DbTxn<'a, #[cfg(feature = "sqlite")] B = Sqlite, #[cfg(not(feature = "sqlite"))] B> = DbReadTxn<'a, B>;
+
+impl<'a, B: Backend> DbReadTxn<'a, B> {
+    // time since begin
+    pub fn age(&self) -> Duration {
+        self.1.elapsed()
+    }
+
+    pub fn conn(&mut self) -> B::Exec<'_> {
+        B::exec(&mut self.0)
+    }
+
+    pub async fn commit(self) -> Result<(), sqlx::Error> {
+        self.0.commit().await
+    }
+
+    pub async fn rollback(self) -> Result<(), sqlx::Error> {
+        self.0.rollback().await
+    }
+}
+
+impl<'a, B: Backend> Deref for DbReadTxn<'a, B> {
+    type Target = DbConnection<B>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a, B: Backend> DerefMut for DbReadTxn<'a, B> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<'a, B: Backend> DbWriteTxn<'a, B> {
+    pub fn audit(&self) -> Option<&AuditContext> {
+        self.1.as_ref()
+    }
+
+    pub fn set_audit(&mut self, ctx: AuditContext) {
+        self.1 = Some(ctx);
+    }
+
+    pub async fn commit(self) -> Result<(), sqlx::Error> {
+        self.0.commit().await
+    }
+
+    pub async fn rollback(self) -> Result<(), sqlx::Error> {
+        self.0.rollback().await
+    }
+}
+
+impl<'a, B: Backend> Deref for DbWriteTxn<'a, B> {
+    type Target = DbReadTxn<'a, B>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a, B: Backend> DerefMut for DbWriteTxn<'a, B> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+// what the transaction middleware keeps for ReadTxn and WriteTxn
+pub enum DbAnyTxn<'a, #[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend> {
+    Read(DbReadTxn<'a, B>),
+    Write(DbWriteTxn<'a, B>),
+}
+
+impl<'a, B: Backend> DbAnyTxn<'a, B> {
+    pub async fn commit(self) -> Result<(), sqlx::Error> {
+        match self {
+            DbAnyTxn::Read(txn) => txn.commit().await,
+            DbAnyTxn::Write(txn) => txn.commit().await,
+        }
+    }
+
+    pub async fn rollback(self) -> Result<(), sqlx::Error> {
+        match self {
+            DbAnyTxn::Read(txn) => txn.rollback().await,
+            DbAnyTxn::Write(txn) => txn.rollback().await,
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        match self {
+            DbAnyTxn::Read(txn) => txn.age(),
+            DbAnyTxn::Write(txn) => txn.age(),
+        }
+    }
+
+    pub fn read(&mut self) -> &mut DbReadTxn<'a, B> {
+        match self {
+            DbAnyTxn::Read(txn) => txn,
+            DbAnyTxn::Write(txn) => txn,
+        }
+    }
+
+    pub fn write(&mut self) -> Option<&mut DbWriteTxn<'a, B>> {
+        match self {
+            DbAnyTxn::Read(_) => None,
+            DbAnyTxn::Write(txn) => Some(txn),
+        }
+    }
+}
+
+impl<'a, B: Backend> From<DbReadTxn<'a, B>> for DbAnyTxn<'a, B> {
+    fn from(txn: DbReadTxn<'a, B>) -> Self {
+        DbAnyTxn::Read(txn)
+    }
 }
 
-impl DbMain {
-    pub fn new(r: Pool<Sqlite>, w: Pool<Sqlite>) -> DbMain {
+impl<'a, B: Backend> From<DbWriteTxn<'a, B>> for DbAnyTxn<'a, B> {
+    fn from(txn: DbWriteTxn<'a, B>) -> Self {
+        DbAnyTxn::Write(txn)
+    }
+}
+
+pub struct DbMain<#[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend> {
+    readers: DbPool<B>,
+    writer: DbPool<B>
+}
+
+// derived Clone would want B: Clone
+impl<B: Backend> Clone for DbMain<B> {
+    fn clone(&self) -> Self {
+        DbMain { readers: self.readers.clone(), writer: self.writer.clone() }
+    }
+}
+
+// for maintenance, anything writing must go through the writer to queue behind transactions
+#[cfg(feature = "sqlite")]
+impl DbMain<Sqlite> {
+    pub(crate) fn writer_pool(&self) -> &DbPool<Sqlite> {
+        &self.writer
+    }
+
+    pub(crate) fn reader_pool(&self) -> &DbPool<Sqlite> {
+        &self.readers
+    }
+}
+
+impl<B: Backend> DbMain<B> {
+    pub fn new(r: DbPool<B>, w: DbPool<B>) -> DbMain<B> {
         DbMain {
             readers: r,
             writer: w
         }
     }
 
-    pub async fn newtx_read(&self) -> anyhow::Result<DbReadTxn<'static>> {
-        Ok(self.readers.begin().await?)
+    // a connection outside both pools, for work that must neither hold the writer nor be read only (backups)
+    pub async fn connect_detached(&self) -> anyhow::Result<DbConnection<B>> {
+        let opts = self.writer.connect_options();
+        Ok(sqlx::ConnectOptions::connect(opts.as_ref()).await?)
+    }
+
+    pub async fn newtx_read(&self) -> anyhow::Result<DbReadTxn<'static, B>> {
+        self.newtx_read_for(JOB_ROUTE).await
     }
 
-    pub async fn newtx_write(&self) -> anyhow::Result<DbWriteTxn<'static>> {
-        Ok(self.writer.begin().await?)
+    // waits for a writer connection (the single one on sqlite), see db_writer_acquire_seconds for contention
+    pub async fn newtx_write(&self) -> anyhow::Result<DbWriteTxn<'static, B>> {
+        self.newtx_write_for(JOB_ROUTE).await
     }
+
+    // like newtx_read, the wait is recorded with `route` as its label (the route pattern in requests)
+    pub async fn newtx_read_for(&self, route: &str) -> anyhow::Result<DbReadTxn<'static, B>> {
+        let started = std::time::Instant::now();
+        let txn = self.readers.begin().await?;
+        histogram!("db_reader_acquire_seconds", started.elapsed().as_secs_f64(), "route" => route.to_string());
+        Ok(DbReadTxn(txn, std::time::Instant::now()))
+    }
+
+    pub async fn newtx_write_for(&self, route: &str) -> anyhow::Result<DbWriteTxn<'static, B>> {
+        let started = std::time::Instant::now();
+        let txn = self.writer.begin().await?;
+        histogram!("db_writer_acquire_seconds", started.elapsed().as_secs_f64(), "route" => route.to_string());
+        Ok(DbWriteTxn(DbReadTxn(txn, std::time::Instant::now()), None))
+    }
+
+    /// Runs the block in a write transaction outside of actix (jobs, CLI), committing when it returns
+    /// Ok and rolling back otherwise. The block returns a boxed future so it can borrow the transaction:
+    /// `db.with_write(|txn| Box::pin(async move { update_field::<X, _>(txn, ...).await })).await`
+    pub async fn with_write<R, F>(&self, block: F) -> anyhow::Result<R>
+        where F: for<'t> FnMut(&'t mut DbWriteTxn<'static, B>) -> TxnBlockFuture<'t, R>
+    {
+        self.with_write_retry(&RetryPolicy::none(), block).await
+    }
+
+    // like with_write, replays the block on busy errors, so it must not have side effects outside the txn
+    pub async fn with_write_retry<R, F>(&self, retry: &RetryPolicy, mut block: F) -> anyhow::Result<R>
+        where F: for<'t> FnMut(&'t mut DbWriteTxn<'static, B>) -> TxnBlockFuture<'t, R>
+    {
+        let mut retries = 0;
+        loop {
+            let res = match self.newtx_write().await {
+                Ok(mut txn) => {
+                    let res = block(&mut txn).await;
+                    close_block_txn(txn.into(), res).await
+                },
+                Err(e) => Err(e)
+            };
+            match res {
+                Err(e) if is_busy_error::<B>(&e) => {
+                    counter!("txn_busy", 1, "route" => JOB_ROUTE);
+                    if retries >= retry.max_retries {
+                        log::warn!("Database busy, giving up after {retries} retries: {e:?}");
+                        return Err(ApiError::LockError.into())
+                    }
+                    retries += 1;
+                    tokio::time::sleep(retry.delay(retries)).await;
+                },
+                other => return other
+            }
+        }
+    }
+
+    // same as with_write on a reader transaction
+    pub async fn with_read<R, F>(&self, mut block: F) -> anyhow::Result<R>
+        where F: for<'t> FnMut(&'t mut DbReadTxn<'static, B>) -> TxnBlockFuture<'t, R>
+    {
+        let mut txn = self.newtx_read().await?;
+        let res = block(&mut txn).await;
+        close_block_txn(txn.into(), res).await
+    }
+}
+
+pub type TxnBlockFuture<'t, R> = BoxFuture<'t, anyhow::Result<R>>;
+
+// metrics label for transactions outside of requests
+const JOB_ROUTE: &str = "job";
+
+// commit or rollback with metrics, shared with TxnMiddleware
+pub(crate) async fn close_txn<B: Backend>(txn: DbAnyTxn<'_, B>, commit: bool, route: &str) -> Result<(), sqlx::Error> {
+    let age = txn.age();
+    let closing = std::time::Instant::now();
+    let closed = if commit { txn.commit().await } else { txn.rollback().await };
+    let outcome = if commit { "commit" } else { "rollback" };
+    histogram!("txn_close_seconds", closing.elapsed().as_secs_f64(), "route" => route.to_string(), "outcome" => outcome);
+    histogram!("txn_duration_seconds", (age + closing.elapsed()).as_secs_f64(), "route" => route.to_string(), "outcome" => outcome);
+    if closed.is_err() {
+        counter!("txn_close_errors", 1, "route" => route.to_string(), "outcome" => outcome);
+    } else {
+        counter!(if commit { "txn_commits" } else { "txn_rollbacks" }, 1, "route" => route.to_string());
+    }
+    closed
 }
 
-pub async fn db_init(url: &str, migrator: &sqlx::migrate::Migrator) -> anyhow::Result<DbMain> {
-    let opts = SqliteConnectOptions::from_str(url)?
-        //.busy_timeout(Duration::from_secs(11))
-        .journal_mode(SqliteJournalMode::Wal)
-        .create_if_missing(true);
+async fn close_block_txn<R, B: Backend>(txn: DbAnyTxn<'_, B>, res: anyhow::Result<R>) -> anyhow::Result<R> {
+    match res {
+        Ok(it) => {
+            close_txn(txn, true, JOB_ROUTE).await?;
+            Ok(it)
+        },
+        Err(e) => {
+            if let Err(rollback_err) = close_txn(txn, false, JOB_ROUTE).await {
+                log::error!("Transaction rollback failed: {rollback_err:?}. Also the original error was: {e:?}");
+            }
+            Err(e)
+        }
+    }
+}
 
-    let wpool = SqlitePoolOptions::new()
-        .max_connections(1)
-        .connect_with(opts.clone()).await?;
+pub async fn db_init<B: Backend>(url: &str, migrator: &sqlx::migrate::Migrator) -> anyhow::Result<DbMain<B>> {
+    db_init_with(url, migrator, &DbSettings::default()).await
+}
 
-    migrator.run(&wpool).await?;
+pub async fn db_init_with<B: Backend>(url: &str, migrator: &sqlx::migrate::Migrator, settings: &DbSettings) -> anyhow::Result<DbMain<B>> {
+    settings.validate()?;
+    let (readers, writer) = B::connect(url, migrator, settings).await?;
+    Ok(DbMain::new(readers, writer))
+}
 
-    let rpool = SqlitePoolOptions::new()
-        .connect_with(opts).await?;
+// lock contention the backend reports (see Backend::is_busy), or waiting for a connection timed out
+pub fn is_busy_error<B: Backend>(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| match cause.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(dbe)) => B::is_busy(dbe.as_ref()),
+        Some(sqlx::Error::PoolTimedOut) => true,
+        _ => false
+    })
+}
 
-    // NOTES
-    // We are using WAL mode (which is default). In this mode there can be many readers with one writer at a time.
-    // sqlx is planning to support W-only and R-only pools where the W-only pool has size 1. Unfortunately,
-    // this is not available yet. This may be more efficient than having a timeout.
-    // Sqlx has a separate thread pool that queries are dispatched to (apparently). So even running a single thread
-    // code will log queries from multiple threads / (connections??)
-    // The limit on one writer at a time applies to a whole transaction actually.
 
-    Ok(DbMain::new(rpool, wpool))
+// extra condition that hides soft deleted rows, `sep` is AND or WHERE
+fn not_deleted<T: TableMetadata>(sep: &str) -> String {
+    match T::deleted_column() {
+        Some(col) => format!(" {sep} {col} IS NULL"),
+        None => String::new(),
+    }
 }
 
 
-pub trait TableMetadata {
-    fn table_name() -> &'static str;
+pub async fn find_opt_field<'a, 'f, T, F, B: Backend>(db: &mut DbTxn<'_, B>, f: &str, v: &'f F) -> anyhow::Result<Option<T>>
+    where T: TableMetadata + for<'r> FromRow<'r, DbRow<B>> + Send + Unpin,
+          F: Sync + Send + sqlx::Encode<'f, B::Db> + sqlx::Type<B::Db>
+{
+    find_opt_field_impl(db, f, v, false).await
 }
 
-pub async fn find_opt_field<'a, 'f, T, F>(db: &mut DbTxn<'_>, f: &str, v: &'f F) -> anyhow::Result<Option<T>>
-    where T: TableMetadata + for<'r> FromRow<'r, SqliteRow> + Send + Unpin,
-          F: Sync + Send + sqlx::Encode<'f, Sqlite> + sqlx::Type<Sqlite>
+async fn find_opt_field_impl<'f, T, F, B: Backend>(db: &mut DbTxn<'_, B>, f: &str, v: &'f F, with_deleted: bool) -> anyhow::Result<Option<T>>
+    where T: TableMetadata + for<'r> FromRow<'r, DbRow<B>> + Send + Unpin,
+          F: Sync + Send + sqlx::Encode<'f, B::Db> + sqlx::Type<B::Db>
 {
     let table = T::table_name();
-    let querystr = format!("SELECT * FROM {table} WHERE {f} = ?");
+    let f = checked_column::<T>(f)?;
+    let live = if with_deleted { String::new() } else { not_deleted::<T>("AND") };
+    let querystr = format!("SELECT * FROM {table} WHERE {f} = $1{live}");
 
-    // this is how we construct arguments ...
-    let mut tmp = sqlx::query("").bind(v);
+    let mut args = DbArguments::<B>::default();
+    args.add(v);
 
-    Ok(sqlx::query_as_with(&querystr, tmp.take_arguments().unwrap())
-        .fetch_optional(&mut **db).await?)
+    Ok(sqlx::query_as_with(&querystr, Args::<B>::from_arguments(args))
+        .fetch_optional(db.conn()).await?)
 }
 
-pub async fn update_field<'a, 'f, T, F>(db: &mut DbTxn<'_>, f: &str, v: &'f F, id: &'f str) -> anyhow::Result<()>
-    where T: TableMetadata + for<'r> FromRow<'r, SqliteRow> + Send + Unpin,
-          F: Sync + Send + sqlx::Encode<'f, Sqlite> + sqlx::Type<Sqlite>
+// returns whether the row existed, soft deleted rows are left alone (restore them first)
+pub async fn update_field<'a, 'f, T, F, B: Backend>(db: &mut DbWriteTxn<'_, B>, f: &str, v: &'f F, id: &'f str) -> anyhow::Result<bool>
+    where T: TableMetadata + for<'r> FromRow<'r, DbRow<B>> + Send + Unpin,
+          F: Sync + Send + sqlx::Encode<'f, B::Db> + sqlx::Type<B::Db>
 {
     let table = T::table_name();
-    let querystr = format!("UPDATE {table} SET {f} = ? WHERE id = ?");
+    let idcol = T::id_column();
+    let f = checked_column::<T>(f)?;
+    let querystr = format!("UPDATE {table} SET {f} = $1 WHERE {idcol} = $2{}", not_deleted::<T>("AND"));
+    let old = audit::snapshot(db, table, idcol, id, &[f]).await?;
 
-    let mut tmp = sqlx::query("")
-        .bind(v)
-        .bind(id);
+    let mut args = DbArguments::<B>::default();
+    args.add(v);
+    B::add(&mut args, id);
 
-    sqlx::query_with(&querystr, tmp.take_arguments().unwrap())
-        .execute(&mut **db)
+    let res = sqlx::query_with(&querystr, Args::<B>::from_arguments(args))
+        .execute(db.conn())
         .await?;
 
-    Ok(())
+    if B::rows_affected(&res) > 0 {
+        let new = audit::snapshot(db, table, idcol, id, &[f]).await?;
+        audit::record_change(db, table, AUDIT_UPDATE, Some(id), old, new).await?;
+    }
+    Ok(B::rows_affected(&res) > 0)
 }
 
-// Typed IDs
-#[macro_export]
-macro_rules! typed_id {
-    ($x:ident) => {
-        #[derive(sqlx::Type, Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, Eq, Hash, Default)]
-        #[sqlx(transparent)]
-        pub struct $x(pub String);
+// .- generic CRUD over TableMetadata::columns() -.
 
-        impl From<String> for $x {
-            fn from(s: String) -> Self {
-                Self(s)
-            }
-        }
 
-        impl From<&str> for $x {
-            fn from(s: &str) -> Self {
-                Self(String::from(s))
-            }
-        }
+fn declared_columns<T: TableMetadata>() -> anyhow::Result<&'static [&'static str]> {
+    let cols = T::columns();
+    if cols.is_empty() {
+        return Err(ApiError::InvalidState(format!("{}.columns.missing", T::table_name())).into())
+    }
+    Ok(cols)
+}
 
-        impl AsRef<str> for $x {
-            fn as_ref(&self) -> &str {
-                self.0.as_str()
-            }
-        }
+pub async fn find_by_id<T, B: Backend>(db: &mut DbTxn<'_, B>, id: &str) -> anyhow::Result<Option<T>>
+    where T: TableMetadata + for<'r> FromRow<'r, DbRow<B>> + Send + Unpin
+{
+    find_by_id_impl(db, id, false).await
+}
 
-        impl ToString for $x {
-            fn to_string(&self) -> String {
-                self.0.clone()
-            }
-        }
+// also finds soft deleted rows
+pub async fn find_by_id_with_deleted<T, B: Backend>(db: &mut DbTxn<'_, B>, id: &str) -> anyhow::Result<Option<T>>
+    where T: TableMetadata + for<'r> FromRow<'r, DbRow<B>> + Send + Unpin
+{
+    find_by_id_impl(db, id, true).await
+}
 
-        impl ts_rs::TS for $x {
-            fn name() -> String {
-                String::from("string")
-            }
+// find_opt_field on the id, which generic code binds as text
+async fn find_by_id_impl<T, B: Backend>(db: &mut DbTxn<'_, B>, id: &str, with_deleted: bool) -> anyhow::Result<Option<T>>
+    where T: TableMetadata + for<'r> FromRow<'r, DbRow<B>> + Send + Unpin
+{
+    let table = T::table_name();
+    let idcol = T::id_column();
+    let live = if with_deleted { String::new() } else { not_deleted::<T>("AND") };
+    let querystr = format!("SELECT * FROM {table} WHERE {idcol} = $1{live}");
 
-            fn dependencies() -> Vec<ts_rs::Dependency> {
-                Vec::new()
-            }
+    Ok(sqlx::query_as_with(&querystr, Args::<B>::new().bind(id))
+        .fetch_optional(db.conn()).await?)
+}
 
-            fn transparent() -> bool { false }
-        }
-    };
+pub async fn find_all_by_field<'f, T, F, B: Backend>(db: &mut DbTxn<'_, B>, f: &str, v: &'f F) -> anyhow::Result<Vec<T>>
+    where T: TableMetadata + for<'r> FromRow<'r, DbRow<B>> + Send + Unpin,
+          F: Sync + Send + sqlx::Encode<'f, B::Db> + sqlx::Type<B::Db>
+{
+    let table = T::table_name();
+    let f = checked_column::<T>(f)?;
+    let querystr = format!("SELECT * FROM {table} WHERE {f} = $1{}", not_deleted::<T>("AND"));
+
+    let mut args = DbArguments::<B>::default();
+    args.add(v);
+
+    Ok(sqlx::query_as_with(&querystr, Args::<B>::from_arguments(args))
+        .fetch_all(db.conn()).await?)
 }
 
-#[macro_export]
-macro_rules! row_reader {
-    ($cls:ident of [ $($x:ident),+ ]) => {
-        impl<'r> FromRow<'r, SqliteRow> for $cls {
-            fn from_row(row: &'r SqliteRow) -> Result<Self, sqlx::Error> {
-                Ok(Self {
-                    $(
-                        $x: row.try_get(stringify!($x))?,
-                    )*
-                })
-            }
-        }
+// the result of a `SELECT COUNT(*) AS n`
+pub(crate) fn count_of<B: Backend>(row: &DbRow<B>) -> anyhow::Result<i64> {
+    Ok(B::get::<i64>(row, "n")?)
+}
+
+pub async fn count<T: TableMetadata, B: Backend>(db: &mut DbTxn<'_, B>) -> anyhow::Result<i64> {
+    let table = T::table_name();
+    let row = sqlx::query_with(&format!("SELECT COUNT(*) AS n FROM {table}{}", not_deleted::<T>("WHERE")), Args::<B>::new())
+        .fetch_one(db.conn()).await?;
+    count_of::<B>(&row)
+}
+
+// the columns an insert of row writes and their bound values, without an unset autoincrement id
+fn insert_columns<'q, T: TableRow<B::Db>, B: Backend>(row: &'q T) -> anyhow::Result<(Vec<&'static str>, DbArguments<'q, B>)> {
+    let mut cols = declared_columns::<T>()?.to_vec();
+    let mut args = DbArguments::<B>::default();
+    if row.id_unset() {
+        cols.retain(|it| *it != T::id_column());
+        row.bind_columns_without_id(&mut args);
+    } else {
+        row.bind_columns(&mut args);
+    }
+    Ok((cols, args))
+}
+
+pub async fn insert<T: TableRow<B::Db>, B: Backend>(db: &mut DbWriteTxn<'_, B>, row: &T) -> anyhow::Result<()> {
+    let table = T::table_name();
+    let (cols, args) = insert_columns::<T, B>(row)?;
+    let querystr = format!("INSERT INTO {table} ({}) VALUES ({})", cols.join(", "), placeholders(1, cols.len()));
+
+    write_returning(db, &querystr, args, table, T::id_column(), AUDIT_INSERT, None).await
+}
+
+// insert or, when the id exists, overwrite all other columns
+pub async fn upsert<T: TableRow<B::Db>, B: Backend>(db: &mut DbWriteTxn<'_, B>, row: &T) -> anyhow::Result<()> {
+    let table = T::table_name();
+    let id = T::id_column();
+    let (cols, args) = insert_columns::<T, B>(row)?;
+    let updates = cols.iter()
+        .filter(|it| **it != id)
+        .map(|it| format!("{it} = excluded.{it}"))
+        .collect::<Vec<String>>();
+    let on_conflict = if updates.is_empty() { String::from("NOTHING") } else { format!("UPDATE SET {}", updates.join(", ")) };
+    let querystr = format!("INSERT INTO {table} ({}) VALUES ({}) ON CONFLICT({id}) DO {on_conflict}", cols.join(", "), placeholders(1, cols.len()));
+    let old = match row.id_value() {
+        Some(_) => {
+            let mut idv = DbArguments::<B>::default();
+            row.bind_id(&mut idv);
+            audit::snapshot_by(db, table, id, Args::from_arguments(idv), &[]).await?
+        },
+        None => None,
     };
-}
\ No newline at end of file
+
+    write_returning(db, &querystr, args, table, id, AUDIT_UPSERT, old).await
+}
+
+// runs an insert, when auditing with RETURNING * to record the written row
+async fn write_returning<'q, B: Backend>(db: &mut DbWriteTxn<'_, B>, querystr: &str, args: DbArguments<'q, B>, table: &str, idcol: &str,
+                                        action: &str, old: Option<serde_json::Map<String, serde_json::Value>>) -> anyhow::Result<()> {
+    if db.audit().is_none() {
+        sqlx::query_with(querystr, Args::<B>::from_arguments(args))
+            .execute(db.conn())
+            .await?;
+        return Ok(())
+    }
+    let row = sqlx::query_with(&format!("{querystr} RETURNING *"), Args::<B>::from_arguments(args))
+        .fetch_optional(db.conn())
+        .await?;
+    // DO NOTHING on conflict returns no row
+    if let Some(row) = row {
+        let new = Some(B::row_to_json(&row));
+        let row_id = audit::id_of(&new, idcol);
+        audit::record_change(db, table, action, row_id.as_deref(), old, new).await?;
+    }
+    Ok(())
+}
+
+// hard delete even for tables with a deleted column, returns whether the row existed
+pub async fn delete_by_id<T: TableMetadata, B: Backend>(db: &mut DbWriteTxn<'_, B>, id: &str) -> anyhow::Result<bool> {
+    let table = T::table_name();
+    let idcol = T::id_column();
+    let old = audit::snapshot(db, table, idcol, id, &[]).await?;
+    let res = sqlx::query_with(&format!("DELETE FROM {table} WHERE {idcol} = $1"), Args::<B>::new().bind(id))
+        .execute(db.conn())
+        .await?;
+    if B::rows_affected(&res) > 0 {
+        audit::record_change(db, table, AUDIT_DELETE, Some(id), old, None).await?;
+    }
+    Ok(B::rows_affected(&res) > 0)
+}
+
+fn deleted_column<T: TableMetadata>() -> anyhow::Result<&'static str> {
+    T::deleted_column()
+        .ok_or_else(|| ApiError::InvalidState(format!("{}.deleted.missing", T::table_name())).into())
+}
+
+// returns false when the row doesn't exist or is already deleted
+pub async fn soft_delete<T: TableMetadata, B: Backend>(db: &mut DbWriteTxn<'_, B>, id: &str, now: Instant) -> anyhow::Result<bool> {
+    let table = T::table_name();
+    let idcol = T::id_column();
+    let dcol = deleted_column::<T>()?;
+    let old = audit::snapshot(db, table, idcol, id, &[dcol]).await?;
+    let res = sqlx::query_with(&format!("UPDATE {table} SET {dcol} = $1 WHERE {idcol} = $2 AND {dcol} IS NULL"), Args::<B>::new()
+            .bind(now)
+            .bind(id))
+        .execute(db.conn())
+        .await?;
+    if B::rows_affected(&res) > 0 {
+        let new = audit::snapshot(db, table, idcol, id, &[dcol]).await?;
+        audit::record_change(db, table, AUDIT_SOFT_DELETE, Some(id), old, new).await?;
+    }
+    Ok(B::rows_affected(&res) > 0)
+}
+
+// returns false when the row doesn't exist or is not deleted
+pub async fn restore<T: TableMetadata, B: Backend>(db: &mut DbWriteTxn<'_, B>, id: &str) -> anyhow::Result<bool> {
+    let table = T::table_name();
+    let idcol = T::id_column();
+    let dcol = deleted_column::<T>()?;
+    let old = audit::snapshot(db, table, idcol, id, &[dcol]).await?;
+    let res = sqlx::query_with(&format!("UPDATE {table} SET {dcol} = NULL WHERE {idcol} = $1 AND {dcol} IS NOT NULL"), Args::<B>::new().bind(id))
+        .execute(db.conn())
+        .await?;
+    if B::rows_affected(&res) > 0 {
+        let new = audit::snapshot(db, table, idcol, id, &[dcol]).await?;
+        audit::record_change(db, table, AUDIT_RESTORE, Some(id), old, new).await?;
+    }
+    Ok(B::rows_affected(&res) > 0)
+}
+
+/// Column values for update_fields, checked against T::columns(), e.g. `Assignments::new().set("name", &name).set("updated", now)`
+pub struct Assignments<'q, #[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend> {
+    cols: Vec<&'q str>,
+    args: DbArguments<'q, B>,
+}
+
+impl<'q, B: Backend> Default for Assignments<'q, B> {
+    fn default() -> Self {
+        Assignments { cols: Vec::new(), args: DbArguments::<B>::default() }
+    }
+}
+
+impl<'q, B: Backend> Assignments<'q, B> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set<V>(mut self, col: &'q str, v: V) -> Self
+        where V: 'q + Send + sqlx::Encode<'q, B::Db> + sqlx::Type<B::Db>
+    {
+        self.cols.push(col);
+        self.args.add(v);
+        self
+    }
+}
+
+// returns the number of rows updated, soft deleted rows are not
+pub async fn update_fields<T: TableMetadata, B: Backend>(db: &mut DbWriteTxn<'_, B>, id: &str, set: Assignments<'_, B>) -> anyhow::Result<u64> {
+    if set.cols.is_empty() { return Ok(0) }
+    let table = T::table_name();
+    let idcol = T::id_column();
+    let assigns = set.cols.iter().enumerate()
+        .map(|(i, it)| checked_column::<T>(it).map(|col| format!("{col} = {}", placeholder(i + 1))))
+        .collect::<anyhow::Result<Vec<String>>>()?
+        .join(", ");
+    let querystr = format!("UPDATE {table} SET {assigns} WHERE {idcol} = {}{}", placeholder(set.cols.len() + 1), not_deleted::<T>("AND"));
+    let old = audit::snapshot(db, table, idcol, id, &set.cols).await?;
+
+    // down to the lifetime of id
+    let mut args = B::narrow(set.args);
+    B::add(&mut args, id);
+
+    let res = sqlx::query_with(&querystr, Args::<B>::from_arguments(args))
+        .execute(db.conn())
+        .await?;
+    if B::rows_affected(&res) > 0 {
+        let new = audit::snapshot(db, table, idcol, id, &set.cols).await?;
+        audit::record_change(db, table, AUDIT_UPDATE, Some(id), old, new).await?;
+    }
+    Ok(B::rows_affected(&res))
+}
+
+// .- optimistic locking over TableMetadata::version_column() -.
+
+// the update matched nothing, either the row is gone (or soft deleted) or somebody else bumped the version
+async fn version_mismatch<T: TableMetadata, B: Backend>(db: &mut DbWriteTxn<'_, B>, id: &str) -> anyhow::Error {
+    let table = T::table_name();
+    let idcol = T::id_column();
+    let exists = sqlx::query_with(&format!("SELECT COUNT(*) AS n FROM {table} WHERE {idcol} = $1{}", not_deleted::<T>("AND")), Args::<B>::new().bind(id))
+        .fetch_one(db.conn())
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|row| count_of::<B>(&row));
+    match exists {
+        Ok(0) => ApiError::NotFound(table.into()).into(),
+        Ok(_) => ApiError::Conflict(format!("{table}.version.stale")).into(),
+        Err(e) => e,
+    }
+}
+
+/// Like update_fields, but only when the row is still at `version`. Bumps the version and returns the new
+/// one, fails with ApiError::Conflict when the row was changed in between and NotFound when it is (soft) deleted.
+pub async fn update_fields_versioned<T: TableMetadata, B: Backend>(db: &mut DbWriteTxn<'_, B>, id: &str, version: i64, set: Assignments<'_, B>) -> anyhow::Result<i64> {
+    let table = T::table_name();
+    let idcol = T::id_column();
+    let vcol = T::version_column()
+        .ok_or_else(|| ApiError::InvalidState(format!("{table}.version.missing")))?;
+    let mut assigns = set.cols.iter().enumerate()
+        .map(|(i, it)| checked_column::<T>(it).map(|col| format!("{col} = {}", placeholder(i + 1))))
+        .collect::<anyhow::Result<Vec<String>>>()?;
+    assigns.push(format!("{vcol} = {vcol} + 1"));
+    let n = set.cols.len();
+    let querystr = format!("UPDATE {table} SET {} WHERE {idcol} = {} AND {vcol} = {}{}", assigns.join(", "), placeholder(n + 1), placeholder(n + 2), not_deleted::<T>("AND"));
+    let mut audited = set.cols.clone();
+    audited.push(vcol);
+    let old = audit::snapshot(db, table, idcol, id, &audited).await?;
+
+    // down to the lifetime of id
+    let mut args = B::narrow(set.args);
+    B::add(&mut args, id);
+    B::add(&mut args, version);
+
+    let res = sqlx::query_with(&querystr, Args::<B>::from_arguments(args))
+        .execute(db.conn())
+        .await?;
+    if B::rows_affected(&res) == 0 {
+        return Err(version_mismatch::<T, B>(db, id).await)
+    }
+    let new = audit::snapshot(db, table, idcol, id, &audited).await?;
+    audit::record_change(db, table, AUDIT_UPDATE, Some(id), old, new).await?;
+    Ok(version + 1)
+}
+
+pub async fn update_field_versioned<'f, T, F, B: Backend>(db: &mut DbWriteTxn<'_, B>, f: &'f str, v: &'f F, id: &str, version: i64) -> anyhow::Result<i64>
+    where T: TableMetadata,
+          F: Sync + Send + sqlx::Encode<'f, B::Db> + sqlx::Type<B::Db>
+{
+    update_fields_versioned::<T, B>(db, id, version, Assignments::new().set(f, v)).await
+}
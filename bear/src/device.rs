@@ -0,0 +1,130 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::web;
+use hex::ToHex;
+use serde::Serialize;
+use crate::backend::{Args, Backend, BackendSql};
+use crate::authmw::{Authentication, parse_header, PrincipalDevice, PrincipalInner, PrincipalOidc};
+use crate::db::{DbTxn, DbWriteTxn};
+use crate::errors::{AnyHandlerError, ApiError};
+use crate::interface::AppContainer;
+use crate::txnmw::WriteTxn;
+use crate::utils::{gentoken, Instant};
+
+// Devices authenticate with `Authorization: Device <device_id> <token>`. Only the sha256 of the token
+// is stored, so a leaked table does not leak credentials. Rotation invalidates the previous token.
+
+pub const SESSION_KIND_DEVICE: &str = "Device";
+pub const DEVICE_AUTH_SCHEME: &str = "Device";
+
+// add to app migrations, in the version for the backend
+pub const DEVICE_SCHEMA: BackendSql = BackendSql { sqlite: DEVICE_SQLITE, postgres: DEVICE_POSTGRES };
+
+const DEVICE_SQLITE: &str = "
+CREATE TABLE device (
+    id TEXT PRIMARY KEY NOT NULL,
+    token_hash TEXT NOT NULL,
+    parent TEXT,
+    created INTEGER NOT NULL,
+    rotated INTEGER NOT NULL
+);
+";
+
+const DEVICE_POSTGRES: &str = "
+CREATE TABLE device (
+    id TEXT PRIMARY KEY NOT NULL,
+    token_hash TEXT NOT NULL,
+    parent TEXT,
+    created BIGINT NOT NULL,
+    rotated BIGINT NOT NULL
+);
+";
+
+#[derive(Debug, Serialize, ts_rs::TS)]
+pub struct DeviceCredentials {
+    pub device_id: String,
+    pub token: String,
+}
+
+fn hash_token(token: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, token.as_bytes()).encode_hex()
+}
+
+pub async fn register_device<B: Backend>(db: &mut DbWriteTxn<'_, B>, parent: Option<&str>, now: Instant) -> anyhow::Result<DeviceCredentials> {
+    let creds = DeviceCredentials { device_id: gentoken(), token: gentoken() };
+    sqlx::query_with("INSERT INTO device (id, token_hash, parent, created, rotated) VALUES ($1, $2, $3, $4, $5)", Args::<B>::new()
+            .bind(&creds.device_id)
+            .bind(hash_token(&creds.token))
+            .bind(parent)
+            .bind(now)
+            .bind(now))
+        .execute(db.conn())
+        .await?;
+    Ok(creds)
+}
+
+pub async fn rotate_device_token<B: Backend>(db: &mut DbWriteTxn<'_, B>, device_id: &str, now: Instant) -> anyhow::Result<DeviceCredentials> {
+    let token: String = gentoken();
+    let res = sqlx::query_with("UPDATE device SET token_hash = $1, rotated = $2 WHERE id = $3", Args::<B>::new()
+            .bind(hash_token(&token))
+            .bind(now)
+            .bind(device_id))
+        .execute(db.conn())
+        .await?;
+    if B::rows_affected(&res) == 0 { return Err(ApiError::NotFound("device".into()).into()) }
+    Ok(DeviceCredentials { device_id: device_id.into(), token })
+}
+
+pub async fn delete_device<B: Backend>(db: &mut DbWriteTxn<'_, B>, device_id: &str) -> anyhow::Result<()> {
+    sqlx::query_with("DELETE FROM device WHERE id = $1", Args::<B>::new().bind(device_id))
+        .execute(db.conn())
+        .await?;
+    Ok(())
+}
+
+// goes from Authentication of kind SESSION_KIND_DEVICE to principal, used by the auth middleware
+pub async fn verify_device<B: Backend>(db: &mut DbTxn<'_, B>, auth: &Authentication) -> anyhow::Result<PrincipalInner> {
+    let row = sqlx::query_with("SELECT token_hash, parent FROM device WHERE id = $1", Args::<B>::new().bind(&auth.id))
+        .fetch_optional(db.conn())
+        .await?
+        .ok_or(ApiError::AuthError1("device.unknown".into()))?;
+    let stored: String = B::get(&row, "token_hash")?;
+    ring::constant_time::verify_slices_are_equal(stored.as_bytes(), hash_token(&auth.secret).as_bytes())
+        .map_err(|_| ApiError::AuthError1("device.token.invalid".into()))?;
+
+    Ok(PrincipalInner {
+        auth_kind: SESSION_KIND_DEVICE.into(),
+        principal: auth.id.clone(),
+        parent: B::get(&row, "parent")?,
+        impersonator: None,
+    })
+}
+
+// for use in AppContainer::read_authentication
+pub fn read_device_authentication(req: &ServiceRequest) -> Option<Authentication> {
+    let header = req.headers().get("Authorization")?.to_str().ok()?;
+    let parts: Vec<&str> = header.split_whitespace().collect();
+    if parts.first() != Some(&DEVICE_AUTH_SCHEME) { return None }
+    let (id, secret) = parse_header(parts)?;
+    Some(Authentication { kind: SESSION_KIND_DEVICE.into(), id, secret })
+}
+
+// devices are registered by a logged in user, who becomes their parent. For other setups (e.g. the shop as
+// parent, or open registration) call register_device from an app handler.
+pub async fn device_register<AC: AppContainer + 'static>(
+    objs: web::Data<AC>,
+    mut txn: WriteTxn<'_, AC::B>,
+    user: PrincipalOidc,
+) -> Result<web::Json<DeviceCredentials>, AnyHandlerError> {
+    let creds = register_device(txn.get(), Some(&user.email), objs.utcnow()).await?;
+    log::info!("Registered device {} for {}", creds.device_id, user.email);
+    Ok(web::Json(creds))
+}
+
+pub async fn device_rotate<AC: AppContainer + 'static>(
+    objs: web::Data<AC>,
+    mut txn: WriteTxn<'_, AC::B>,
+    device: PrincipalDevice,
+) -> Result<web::Json<DeviceCredentials>, AnyHandlerError> {
+    let creds = rotate_device_token(txn.get(), &device.device_id, objs.utcnow()).await?;
+    Ok(web::Json(creds))
+}
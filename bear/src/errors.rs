@@ -1,9 +1,8 @@
 use std::fmt::{Debug, Display, Formatter};
 use std::future::{Ready, ready};
 use std::rc::Rc;
-use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
-use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, web};
-use anyhow::anyhow;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::Error;
 use futures_util::future::LocalBoxFuture;
 use actix_web::body::BoxBody;
 use actix_web::{HttpResponse, HttpResponseBuilder};
@@ -59,13 +58,19 @@ pub enum ApiError {
     Unauthorized,
     #[error("Disabled")]
     Disabled,
+    #[error("UnknownColumn({0})")]
+    UnknownColumn(String), // table.column, a column name not in TableMetadata::columns()
+    #[error("Conflict({0})")]
+    Conflict(String), // the row changed since it was read, see update_fields_versioned
+    #[error("BodyTooLarge")]
+    BodyTooLarge, // request body over RetryPolicy::max_body, 413
 }
 
 pub fn map_os_err<R, T : Debug>(v: Result<R, T>) -> std::io::Result<R> {
     v.map_err(|e| {
         let estr = format!("{:?}", e);
         log::error!("Error when starting:[{:?}", estr.clone());
-        std::io::Error::new(std::io::ErrorKind::Other, estr)
+        std::io::Error::other(estr)
     })
 }
 
@@ -79,6 +84,12 @@ pub fn anyerr_in(code: &str) -> anyhow::Error {
 
 pub struct AnyHandlerError(anyhow::Error);
 
+impl AnyHandlerError {
+    pub fn inner(&self) -> &anyhow::Error {
+        &self.0
+    }
+}
+
 impl Debug for AnyHandlerError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         Debug::fmt(&self.0, f)
@@ -105,7 +116,7 @@ impl<E> From<E> for AnyHandlerError
 impl From<anyhow::Error> for AnyHandlerError
 {
     fn from(e: anyhow::Error) -> Self {
-        AnyHandlerError(e.into())
+        AnyHandlerError(e)
     }
 }
 
@@ -133,6 +144,15 @@ impl actix_web::ResponseError for AnyHandlerError {
         let (errstr, code) = match self.0.downcast_ref::<ApiError>() {
             Some(t @ ApiError::AuthError) | Some(t @ ApiError::Expired) | Some(t @ ApiError::AuthError1(_)) =>
                 (t.to_string(), http::status::StatusCode::UNAUTHORIZED),
+            Some(t @ ApiError::LockError) =>
+                (t.to_string(), http::status::StatusCode::SERVICE_UNAVAILABLE),
+            Some(t @ ApiError::Conflict(_)) =>
+                (t.to_string(), http::status::StatusCode::CONFLICT),
+            Some(t @ ApiError::BodyTooLarge) =>
+                (t.to_string(), http::status::StatusCode::PAYLOAD_TOO_LARGE),
+            // a sort or filter column from the request that the table doesn't have
+            Some(t @ ApiError::UnknownColumn(_)) =>
+                (t.to_string(), http::status::StatusCode::BAD_REQUEST),
             Some(t) =>
                 (t.to_string(), http::status::StatusCode::BAD_REQUEST),
             None => {
@@ -0,0 +1,52 @@
+use std::future::{Ready, ready};
+use actix_web::{Error, FromRequest, HttpRequest};
+use actix_web::dev::Payload;
+use actix_web::http::header::{ETag, EntityTag, Header, IfMatch};
+use crate::errors::{AnyHandlerError, ApiError};
+
+// HTTP side of optimistic locking. GET handlers return the row version as ETag, edits send it back in
+// If-Match and pass it to update_fields_versioned, a stale version becomes 409.
+//
+//   HttpResponse::Ok().insert_header(etag(order.version)).json(order)
+//
+//   async fn edit(mut txn: WriteTxn<'_>, ifm: IfMatchVersion, ...) -> ... {
+//       update_fields_versioned::<Order>(txn.get(), &id, ifm.required()?, Assignments::new().set("note", &note)).await?;
+
+pub fn etag(version: i64) -> ETag {
+    ETag(EntityTag::new_strong(version.to_string()))
+}
+
+/// Version from the If-Match header, None when absent or `*`.
+#[derive(Debug, Clone, Copy)]
+pub struct IfMatchVersion(pub Option<i64>);
+
+impl IfMatchVersion {
+    // for handlers that refuse blind overwrites
+    pub fn required(&self) -> anyhow::Result<i64> {
+        self.0.ok_or_else(|| ApiError::Any("if-match.missing".into()).into())
+    }
+
+    pub fn parse(req: &HttpRequest) -> anyhow::Result<IfMatchVersion> {
+        if !req.headers().contains_key(IfMatch::name()) {
+            return Ok(IfMatchVersion(None))
+        }
+        let version = match IfMatch::parse(req).map_err(|_| ApiError::InvalidInput)? {
+            IfMatch::Any => None,
+            // several tags make no sense for a single row
+            IfMatch::Items(tags) => match tags.as_slice() {
+                [tag] if !tag.weak => Some(tag.tag().parse::<i64>().map_err(|_| ApiError::InvalidInput)?),
+                _ => return Err(ApiError::InvalidInput.into()),
+            },
+        };
+        Ok(IfMatchVersion(version))
+    }
+}
+
+impl FromRequest for IfMatchVersion {
+    type Error = Error;
+    type Future = Ready<Result<IfMatchVersion, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(IfMatchVersion::parse(req).map_err(|e| AnyHandlerError::from(e).into()))
+    }
+}
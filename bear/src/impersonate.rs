@@ -0,0 +1,171 @@
+use std::rc::Rc;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, HttpResponseBuilder, Responder, web};
+use actix_web::dev::Payload;
+use futures_util::future::LocalBoxFuture;
+use http::StatusCode;
+use serde::Deserialize;
+use crate::backend::{Args, Backend, BackendSql};
+use crate::authmw::{PrincipalAdmin, PrincipalAny, PrincipalInner};
+use crate::cfg::Cfg;
+use crate::db::DbWriteTxn;
+use crate::errors::{AnyHandlerError, ApiError};
+use crate::interface::{AppContainer, Session};
+use crate::oidc::{SESSION_COOKIE_NAME, SESSION_KIND_OIDC};
+use crate::txnmw::WriteTxn;
+use crate::utils::{Instant, std_cookie};
+
+// Admin impersonation. An admin logged in via OIDC creates a short-lived session that authenticates
+// as another principal. The admin is carried in PrincipalInner::impersonator and every request
+// made with such a session is recorded in the audit table below (same txn as the session check).
+// The session keeps the admin's own one, impersonate_stop switches the cookie back to it.
+
+// seconds, impersonation sessions are never extended
+pub const IMPERSONATION_LIFETIME: i64 = 15 * 60;
+
+// add to app migrations, in the version for the backend
+pub const IMPERSONATION_AUDIT_SCHEMA: BackendSql = BackendSql { sqlite: IMPERSONATION_AUDIT_SQLITE, postgres: IMPERSONATION_AUDIT_POSTGRES };
+
+const IMPERSONATION_AUDIT_SQLITE: &str = "
+CREATE TABLE impersonation_audit (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    admin TEXT NOT NULL,
+    auth_kind TEXT NOT NULL,
+    principal TEXT NOT NULL,
+    method TEXT NOT NULL,
+    path TEXT NOT NULL,
+    created INTEGER NOT NULL
+);
+CREATE INDEX impersonation_audit_admin ON impersonation_audit(admin, created);
+";
+
+const IMPERSONATION_AUDIT_POSTGRES: &str = "
+CREATE TABLE impersonation_audit (
+    id BIGSERIAL PRIMARY KEY,
+    admin TEXT NOT NULL,
+    auth_kind TEXT NOT NULL,
+    principal TEXT NOT NULL,
+    method TEXT NOT NULL,
+    path TEXT NOT NULL,
+    created BIGINT NOT NULL
+);
+CREATE INDEX impersonation_audit_admin ON impersonation_audit(admin, created);
+";
+
+pub async fn record_impersonation<B: Backend>(db: &mut DbWriteTxn<'_, B>, admin: &str, principal: &PrincipalInner, method: &str, path: &str, now: Instant) -> anyhow::Result<()> {
+    sqlx::query_with("INSERT INTO impersonation_audit (admin, auth_kind, principal, method, path, created) VALUES ($1, $2, $3, $4, $5, $6)", Args::<B>::new()
+            .bind(admin)
+            .bind(&principal.auth_kind)
+            .bind(&principal.principal)
+            .bind(method)
+            .bind(path)
+            .bind(now))
+        .execute(db.conn())
+        .await?;
+    Ok(())
+}
+
+// the admin's own session while impersonating, put in the request by the auth middleware
+pub(crate) struct AdminSession(pub String);
+
+/// Tells the handler whether an admin is acting as the principal. Doesn't count as extracting the
+/// principal, handlers under an authenticated scope still need one of the Principal* extractors.
+pub struct Impersonation {
+    pub admin: Option<String>
+}
+
+impl Impersonation {
+    pub fn active(&self) -> bool {
+        self.admin.is_some()
+    }
+}
+
+impl FromRequest for Impersonation {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Impersonation, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let exts = req.extensions();
+        let admin = exts.get::<Rc<PrincipalInner>>()
+            .and_then(|it| it.impersonator.clone());
+
+        Box::pin(async move {
+            Ok(Impersonation { admin })
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImpersonateRequest {
+    pub auth_kind: String,
+    pub principal: String,
+    #[serde(default)]
+    pub parent: Option<String>,
+}
+
+pub async fn impersonate_start<AC>(
+    objs: web::Data<AC>,
+    mut txn: WriteTxn<'_, AC::B>,
+    admin: PrincipalAdmin<AC>,
+    current: Impersonation,
+    req: HttpRequest,
+    body: web::Json<ImpersonateRequest>
+) -> Result<impl Responder, AnyHandlerError>
+    where AC: AppContainer + 'static,
+          AC::S: 'static,
+          AC::Cfg: Cfg + 'static
+{
+    // no chaining, stop the current impersonation first
+    if current.active() { return Err(ApiError::AuthError1("impersonation.already.active".into()).into()) }
+
+    let body = body.into_inner();
+    // admins can't be impersonated, that would hand out the rights of another admin
+    if body.auth_kind == SESSION_KIND_OIDC && objs.cfg().server().oidc_admins.contains(&body.principal) {
+        return Err(ApiError::AuthError1("impersonation.target.admin".into()).into())
+    }
+
+    let admin_session = req.cookie(SESSION_COOKIE_NAME)
+        .ok_or(ApiError::AuthError)?;
+    let target = PrincipalInner {
+        auth_kind: body.auth_kind,
+        principal: body.principal,
+        parent: body.parent,
+        impersonator: Some(admin.email.clone()),
+    };
+    let now = objs.utcnow();
+    let sess = AC::S::new_impersonation(now + IMPERSONATION_LIFETIME, target.clone(), admin_session.value().into());
+    log::warn!("Admin {} impersonating {}:{}", admin.email, target.auth_kind, target.principal);
+    AC::S::insert(txn.get(), &sess).await?;
+    record_impersonation(txn.get(), &admin.email, &target, req.method().as_str(), req.path(), now).await?;
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .cookie(std_cookie(SESSION_COOKIE_NAME, sess.code()))
+        .finish())
+}
+
+pub async fn impersonate_stop<AC>(
+    mut txn: WriteTxn<'_, AC::B>,
+    principal: PrincipalAny,
+    req: HttpRequest,
+) -> Result<impl Responder, AnyHandlerError>
+    where AC: AppContainer + 'static,
+          AC::S: 'static
+{
+    if !principal.0.is_impersonated() { return Err(ApiError::AuthError1("impersonation.not.active".into()).into()) }
+    let code = req.cookie(SESSION_COOKIE_NAME)
+        .ok_or(ApiError::AuthError)?;
+    AC::S::delete(txn.get(), code.value()).await?;
+
+    // back to the admin's session, logged out if the app's sessions don't keep it
+    let admin_session = req.extensions().get::<AdminSession>().map(|it| it.0.clone());
+    let cookie = match admin_session.as_deref() {
+        Some(it) => std_cookie(SESSION_COOKIE_NAME, it),
+        None => {
+            let mut removal = std_cookie(SESSION_COOKIE_NAME, "");
+            removal.make_removal();
+            removal
+        }
+    };
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .cookie(cookie)
+        .finish())
+}
@@ -3,7 +3,10 @@ use actix_web::dev::ServiceRequest;
 use actix_web::web;
 use async_trait::async_trait;
 use crate::authmw::{Authentication, PrincipalInner};
-use crate::db::DbTxn;
+use crate::backend::Backend;
+#[cfg(feature = "sqlite")]
+use crate::backend::Sqlite;
+use crate::db::{DbTxn, DbWriteTxn};
 use crate::utils::Instant;
 
 pub enum CommonSecretKind {
@@ -11,7 +14,8 @@ pub enum CommonSecretKind {
 }
 
 pub trait AppContainer : Send + Sync {
-    type S: Session;
+    type B: Backend; // e.g. bear::backend::Sqlite, see backend.rs
+    type S: Session<Self::B>;
     type Cfg: crate::cfg::Cfg;
 
     fn cfg(&self) -> &Self::Cfg;
@@ -23,18 +27,26 @@ pub trait AppContainer : Send + Sync {
 }
 
 #[async_trait]
-pub trait Session : Sized + Send + Debug {
+pub trait Session<#[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend> : Sized + Send + Debug {
     fn code(&self) -> &str;
     fn expires(&self) -> Instant;
     fn kind(&self) -> String;
     fn email(&self) -> Option<&str>;
+    // email of the admin for impersonation sessions (see new_impersonation), None otherwise. Required so
+    // an app can't forget it: such sessions would be extended and not audited like normal ones.
+    fn impersonator(&self) -> Option<&str>;
+    // code of the admin's own session for impersonation sessions, impersonate_stop puts it back in the cookie
+    fn admin_session(&self) -> Option<&str>;
 
-    async fn find_session<AC: AppContainer>(db: &mut DbTxn<'_>, objs: web::Data<AC>, auth: &Authentication) -> anyhow::Result<Self>;
-    async fn extend(db: &mut DbTxn<'_>, code: &str, expires: Instant) -> anyhow::Result<()>;
-    async fn delete(db: &mut DbTxn<'_>, code: &str) -> anyhow::Result<()>;
-    async fn insert(db: &mut DbTxn<'_>, s: &Self) -> anyhow::Result<()>;
+    async fn find_session<AC: AppContainer>(db: &mut DbTxn<'_, B>, objs: web::Data<AC>, auth: &Authentication) -> anyhow::Result<Self>;
+    async fn extend(db: &mut DbWriteTxn<'_, B>, code: &str, expires: Instant) -> anyhow::Result<()>;
+    async fn delete(db: &mut DbWriteTxn<'_, B>, code: &str) -> anyhow::Result<()>;
+    async fn insert(db: &mut DbWriteTxn<'_, B>, s: &Self) -> anyhow::Result<()>;
 
     fn new_oidc(expires: Instant, email: String) -> Self;
+    // session where the admin in target.impersonator acts as target (kind, principal and parent, which
+    // as_principal gives back), see impersonate.rs
+    fn new_impersonation(expires: Instant, target: PrincipalInner, admin_session: String) -> Self;
     fn lifetime(kind: &str) -> i64;
 
     fn as_principal(&self) -> anyhow::Result<PrincipalInner>;
@@ -1,14 +1,36 @@
-pub mod db;
-pub mod txnmw;
+// lets derived code refer to ::bear from within this crate too
+extern crate self as bear;
+
 pub mod errors;
 pub mod utils;
+pub mod cfg;
+pub mod apispec;
+pub mod cents;
+pub mod etag;
+pub mod table;
+pub mod page;
+
+// see backend for the database backends
+pub mod backend;
+pub mod db;
+pub mod txnmw;
 pub mod testbase;
 pub mod oidc;
 pub mod authmw;
 pub mod interface;
-pub mod cfg;
-pub mod apispec;
-pub mod cents;
+pub mod impersonate;
+pub mod device;
+pub mod outbox;
+pub mod query;
+pub mod retention;
+pub mod audit;
+// sqlite only, they work on the database file
+#[cfg(feature = "sqlite")]
+pub mod backup;
+#[cfg(feature = "sqlite")]
+pub mod maintenance;
+
+pub use bear_derive::BearTable;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,208 @@
+use std::time::Duration;
+use actix_web::web;
+use metrics::{counter, gauge, histogram};
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use tokio::task::JoinHandle;
+use crate::authmw::PrincipalAdmin;
+use crate::db::DbMain;
+use crate::errors::{AnyHandlerError, ApiError};
+use crate::interface::AppContainer;
+
+// Periodic upkeep of the database file. The WAL only shrinks on a TRUNCATE checkpoint, the query planner
+// statistics go stale without PRAGMA optimize, and freed pages stay in the file unless the database uses
+// auto_vacuum=INCREMENTAL and incremental_vacuum runs. quick_check catches corruption early.
+// Writing tasks go through the writer pool so they wait for the current transaction instead of failing.
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct MaintenanceSettings {
+    // seconds between runs, none disables the task
+    pub checkpoint_secs: Option<u64>,
+    pub optimize_secs: Option<u64>,
+    pub incremental_vacuum_secs: Option<u64>,
+    pub quick_check_secs: Option<u64>,
+    // pages freed per incremental vacuum, 0 is all
+    pub vacuum_pages: i64,
+}
+
+impl Default for MaintenanceSettings {
+    fn default() -> Self {
+        Self {
+            checkpoint_secs: Some(15 * 60),
+            optimize_secs: Some(6 * 3600),
+            incremental_vacuum_secs: Some(24 * 3600),
+            quick_check_secs: Some(24 * 3600),
+            vacuum_pages: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ts_rs::TS)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceTask {
+    Checkpoint,
+    Optimize,
+    IncrementalVacuum,
+    QuickCheck,
+}
+
+impl MaintenanceTask {
+    pub const ALL: [MaintenanceTask; 4] = [MaintenanceTask::Checkpoint, MaintenanceTask::Optimize, MaintenanceTask::IncrementalVacuum, MaintenanceTask::QuickCheck];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaintenanceTask::Checkpoint => "checkpoint",
+            MaintenanceTask::Optimize => "optimize",
+            MaintenanceTask::IncrementalVacuum => "incremental_vacuum",
+            MaintenanceTask::QuickCheck => "quick_check",
+        }
+    }
+
+    fn every(&self, settings: &MaintenanceSettings) -> Option<Duration> {
+        let secs = match self {
+            MaintenanceTask::Checkpoint => settings.checkpoint_secs,
+            MaintenanceTask::Optimize => settings.optimize_secs,
+            MaintenanceTask::IncrementalVacuum => settings.incremental_vacuum_secs,
+            MaintenanceTask::QuickCheck => settings.quick_check_secs,
+        };
+        secs.filter(|it| *it > 0).map(Duration::from_secs)
+    }
+}
+
+#[derive(Debug, Serialize, ts_rs::TS)]
+pub struct MaintenanceReport {
+    pub task: MaintenanceTask,
+    pub detail: String,
+    pub millis: u64,
+}
+
+pub async fn run_task(db: &DbMain, task: MaintenanceTask, settings: &MaintenanceSettings) -> anyhow::Result<MaintenanceReport> {
+    let started = std::time::Instant::now();
+    let res = match task {
+        MaintenanceTask::Checkpoint => checkpoint(db).await,
+        MaintenanceTask::Optimize => optimize(db).await,
+        MaintenanceTask::IncrementalVacuum => incremental_vacuum(db, settings.vacuum_pages).await,
+        MaintenanceTask::QuickCheck => quick_check(db).await,
+    };
+    let elapsed = started.elapsed();
+    histogram!("db_maintenance_seconds", elapsed.as_secs_f64(), "task" => task.name());
+    match res {
+        Ok(detail) => {
+            log::info!("DB maintenance {}: {detail} in {}ms", task.name(), elapsed.as_millis());
+            Ok(MaintenanceReport { task, detail, millis: elapsed.as_millis() as u64 })
+        },
+        Err(e) => {
+            log::error!("DB maintenance {} failed: {e:?}", task.name());
+            counter!("db_maintenance_failures", 1, "task" => task.name());
+            Err(e)
+        }
+    }
+}
+
+async fn checkpoint(db: &DbMain) -> anyhow::Result<String> {
+    let mut conn = db.writer_pool().acquire().await?;
+    let row = sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+        .fetch_one(&mut *conn)
+        .await?;
+    let (busy, log, done): (i64, i64, i64) = (row.try_get(0)?, row.try_get(1)?, row.try_get(2)?);
+    gauge!("db_wal_pages", log as f64);
+    // busy means a reader kept the checkpoint from finishing, the next run picks it up
+    if busy != 0 {
+        counter!("db_checkpoint_busy", 1);
+    }
+    Ok(format!("busy={busy} wal_pages={log} checkpointed={done}"))
+}
+
+async fn optimize(db: &DbMain) -> anyhow::Result<String> {
+    let mut conn = db.writer_pool().acquire().await?;
+    sqlx::query("PRAGMA optimize")
+        .execute(&mut *conn)
+        .await?;
+    Ok(String::from("ok"))
+}
+
+async fn incremental_vacuum(db: &DbMain, pages: i64) -> anyhow::Result<String> {
+    let mut conn = db.writer_pool().acquire().await?;
+    let auto_vacuum: i64 = sqlx::query_scalar("PRAGMA auto_vacuum")
+        .fetch_one(&mut *conn)
+        .await?;
+    let before: i64 = sqlx::query_scalar("PRAGMA freelist_count")
+        .fetch_one(&mut *conn)
+        .await?;
+    gauge!("db_freelist_pages", before as f64);
+    // 2 is INCREMENTAL, it can only be turned on for a new database or with a full VACUUM
+    if auto_vacuum != 2 {
+        return Ok(format!("skipped, auto_vacuum={auto_vacuum} freelist_pages={before}"))
+    }
+    sqlx::query(&format!("PRAGMA incremental_vacuum({})", pages.max(0)))
+        .execute(&mut *conn)
+        .await?;
+    let after: i64 = sqlx::query_scalar("PRAGMA freelist_count")
+        .fetch_one(&mut *conn)
+        .await?;
+    gauge!("db_freelist_pages", after as f64);
+    Ok(format!("freelist_pages={before}->{after}"))
+}
+
+async fn quick_check(db: &DbMain) -> anyhow::Result<String> {
+    let mut conn = db.reader_pool().acquire().await?;
+    let res: Vec<String> = sqlx::query_scalar("PRAGMA quick_check")
+        .fetch_all(&mut *conn)
+        .await?;
+    if res != ["ok"] {
+        counter!("db_quick_check_failures", 1);
+        log::error!("Database quick_check failed: {}", res.join("; "));
+        return Err(ApiError::InvalidState(String::from("db.quick_check.failed")).into())
+    }
+    Ok(String::from("ok"))
+}
+
+pub struct Maintenance {
+    db: DbMain,
+    settings: MaintenanceSettings,
+}
+
+impl Maintenance {
+    pub fn new(db: DbMain, settings: MaintenanceSettings) -> Self {
+        Self { db, settings }
+    }
+
+    // runs every enabled task on its own interval, first runs are one interval after start
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let start = tokio::time::Instant::now();
+            let mut due: Vec<(MaintenanceTask, Duration, tokio::time::Instant)> = MaintenanceTask::ALL.iter()
+                .filter_map(|task| task.every(&self.settings).map(|every| (*task, every, start + every)))
+                .collect();
+            if due.is_empty() {
+                log::info!("DB maintenance disabled");
+                return
+            }
+            loop {
+                let next = due.iter().map(|it| it.2).min().unwrap();
+                tokio::time::sleep_until(next).await;
+                let now = tokio::time::Instant::now();
+                for (task, every, at) in due.iter_mut() {
+                    if *at > now { continue }
+                    // errors are logged and counted in run_task
+                    let _ = run_task(&self.db, *task, &self.settings).await;
+                    *at = tokio::time::Instant::now() + *every;
+                }
+            }
+        })
+    }
+}
+
+// admin only, route as e.g. /admin/db/maintenance/{task}, needs web::Data<MaintenanceSettings>
+pub async fn maintenance_run<AC>(
+    db: web::Data<DbMain>,
+    settings: web::Data<MaintenanceSettings>,
+    admin: PrincipalAdmin<AC>,
+    task: web::Path<MaintenanceTask>,
+) -> Result<web::Json<MaintenanceReport>, AnyHandlerError>
+    where AC: AppContainer + 'static
+{
+    log::warn!("DB maintenance {} requested by {}", task.name(), admin.email);
+    Ok(web::Json(run_task(&db, task.into_inner(), &settings).await?))
+}
@@ -23,7 +23,7 @@ pub const SESSION_KIND_OIDC: &str = "Oidc";
 
 fn make_client(cfg: &ServerSettings, secret: String) -> anyhow::Result<oidc::Client> {
     let id = cfg.oidc_client_id.clone();
-    let redirect = url::Url::parse(&format!("{}/api/oidc/callback", cfg.public_url)).map_err(anyhow::Error::from)?;
+    let redirect = url1::Url::parse(&format!("{}/api/oidc/callback", cfg.public_url)).map_err(anyhow::Error::from)?;
     let issuer = oidc::issuer::google();
     let client = oidc::Client::discover(id, secret, redirect, issuer).map_err(anyerr)?;
     Ok(client)
@@ -35,12 +35,15 @@ pub async fn oidc_start<AC: AppContainer + 'static>(objs: web::Data<AC>) -> Resu
 
     let task = tokio::task::spawn_blocking(move || {
         let nonce = ring::digest::digest(&ring::digest::SHA256, bs58::decode(nonce_preimage_cl).into_vec().unwrap().as_slice());
-        let client = make_client(&objs.cfg().server(), objs.secret(CommonSecretKind::OidcSecret).into())?;
-        let mut opts = oidc::Options::default();
-        opts.scope = Some("openid email profile".to_string());
-        opts.state = Some(gentoken());
-        opts.nonce = Some(nonce.encode_hex());
-        Ok::<url::Url, anyhow::Error>(client.auth_url(&opts))
+        let client = make_client(objs.cfg().server(), objs.secret(CommonSecretKind::OidcSecret).into())?;
+        let opts = oidc::Options {
+            scope: Some("openid email profile".to_string()),
+            state: Some(gentoken()),
+            nonce: Some(nonce.encode_hex()),
+            ..Default::default()
+        };
+        // url 1 to 2
+        Ok::<url::Url, anyhow::Error>(url::Url::parse(client.auth_url(&opts).as_str())?)
     });
     let auth_url = task.await.map_err(|e| anyhow!("join error{e}"))??;
 
@@ -65,12 +68,12 @@ fn anyerr<T: Display>(e: T) -> anyhow::Error {
 // BTW getting user info manually because the lib has an old version of reqwest in the interface so can't use it
 pub async fn oidc_callback<AC>(
     objs: web::Data<AC>,
-    mut txn: WriteTxn<'_>,
+    mut txn: WriteTxn<'_, AC::B>,
     req: HttpRequest,
     query: web::Query<OidcCallbackQuery>
 ) -> Result<impl Responder, AnyHandlerError>
     where AC: AppContainer + 'static,
-            AC::S: Session + 'static,
+            AC::S: 'static,
             AC::Cfg: Cfg + 'static
 {
     let nonce_preimage = req.cookie(OIDC_NONCE_COOKIE_NAME)
@@ -85,7 +88,7 @@ pub async fn oidc_callback<AC>(
     );
 
     let task  = tokio::task::spawn_blocking(move || {
-        let client = make_client(&objs.cfg().server(), objs.secret(CommonSecretKind::OidcSecret).into())?;
+        let client = make_client(objs.cfg().server(), objs.secret(CommonSecretKind::OidcSecret).into())?;
         let http = reqwest::blocking::Client::new();
         let token = client.authenticate(&query.code, Some(&exp_nonce.encode_hex::<String>()), None)
             .map_err(anyerr)?;
@@ -98,7 +101,7 @@ pub async fn oidc_callback<AC>(
             .json().map_err(anyerr)?;
 
         if let Jws::Decoded { header: _, payload: _pay } = token.id_token {
-            if (userinfo.email_verified == false) || (userinfo.email.is_none()) { return Err(ApiError::AuthError.into()) }
+            if !userinfo.email_verified || userinfo.email.is_none() { return Err(ApiError::AuthError.into()) }
 
             // no  accepting general users for now
             if !objs.cfg().server().oidc_admins.contains(userinfo.email.as_ref().unwrap()) { return Err(ApiError::AuthError.into()) }
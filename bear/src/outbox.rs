@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use metrics::counter;
+use serde::Serialize;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use crate::backend::{Args, Backend, BackendSql, DbRow};
+#[cfg(feature = "sqlite")]
+use crate::backend::Sqlite;
+use crate::db::{DbMain, DbWriteTxn};
+use crate::utils::{Clock, Instant};
+
+// Transactional outbox. Handlers write messages in their own WriteTxn (so a message exists iff the
+// transaction committed) and a background OutboxDispatcher delivers them with retries. Dispatchers
+// claim their batch in a write transaction (status 'in_flight' until the lease runs out), so several
+// app instances can run one each. Delivery is at least once: a message whose lease expired before the
+// result was recorded, e.g. after a crash, is delivered again.
+// Messages that keep failing end up with status 'dead' and stay in the table for inspection.
+
+// add to app migrations, in the version for the backend
+pub const OUTBOX_SCHEMA: BackendSql = BackendSql { sqlite: OUTBOX_SQLITE, postgres: OUTBOX_POSTGRES };
+
+const OUTBOX_SQLITE: &str = "
+CREATE TABLE outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    topic TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt INTEGER NOT NULL,
+    last_error TEXT,
+    created INTEGER NOT NULL
+);
+CREATE INDEX outbox_due ON outbox(status, next_attempt);
+";
+
+const OUTBOX_POSTGRES: &str = "
+CREATE TABLE outbox (
+    id BIGSERIAL PRIMARY KEY,
+    topic TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL,
+    attempts BIGINT NOT NULL DEFAULT 0,
+    next_attempt BIGINT NOT NULL,
+    last_error TEXT,
+    created BIGINT NOT NULL
+);
+CREATE INDEX outbox_due ON outbox(status, next_attempt);
+";
+
+pub const OUTBOX_PENDING: &str = "pending";
+pub const OUTBOX_IN_FLIGHT: &str = "in_flight";
+pub const OUTBOX_DELIVERED: &str = "delivered";
+pub const OUTBOX_DEAD: &str = "dead";
+
+#[derive(Debug, Clone)]
+pub struct OutboxMessage {
+    pub id: i64,
+    pub topic: String,
+    pub payload: String, // json
+    pub attempts: i64,
+    pub created: Instant,
+    pub lease: Instant, // end of this dispatcher's claim, the result is only recorded while it holds
+}
+
+impl OutboxMessage {
+    fn from_row<B: Backend>(row: &DbRow<B>) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            id: B::get(row, "id")?,
+            topic: B::get(row, "topic")?,
+            payload: B::get(row, "payload")?,
+            attempts: B::get(row, "attempts")?,
+            created: B::get(row, "created")?,
+            lease: B::get(row, "next_attempt")?,
+        })
+    }
+}
+
+pub async fn outbox_put<T: Serialize, B: Backend>(db: &mut DbWriteTxn<'_, B>, topic: &str, payload: &T, now: Instant) -> anyhow::Result<i64> {
+    let row = sqlx::query_with("INSERT INTO outbox (topic, payload, status, attempts, next_attempt, created) VALUES ($1, $2, $3, 0, $4, $5) RETURNING id", Args::<B>::new()
+            .bind(topic)
+            .bind(serde_json::to_string(payload)?)
+            .bind(OUTBOX_PENDING)
+            .bind(now)
+            .bind(now))
+        .fetch_one(db.conn())
+        .await?;
+    Ok(B::get(&row, "id")?)
+}
+
+#[async_trait]
+pub trait OutboxHandler : Send + Sync {
+    async fn deliver(&self, msg: &OutboxMessage) -> anyhow::Result<()>;
+}
+
+/// Posts the payload as json, any non 2xx response counts as failure. Requests time out after
+/// OutboxSettings::delivery_timeout, a hanging receiver would otherwise outlive the lease.
+pub struct HttpOutboxHandler {
+    pub url: String,
+    client: reqwest::Client,
+}
+
+impl HttpOutboxHandler {
+    pub fn new(url: &str, settings: &OutboxSettings) -> anyhow::Result<Self> {
+        Ok(Self {
+            url: url.into(),
+            client: reqwest::Client::builder().timeout(settings.delivery_timeout()).build()?,
+        })
+    }
+}
+
+#[async_trait]
+impl OutboxHandler for HttpOutboxHandler {
+    async fn deliver(&self, msg: &OutboxMessage) -> anyhow::Result<()> {
+        self.client.post(&self.url)
+            .header("Content-Type", "application/json")
+            .header("X-Outbox-Id", msg.id.to_string())
+            .header("X-Outbox-Topic", msg.topic.as_str())
+            .body(msg.payload.clone())
+            .send().await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct OutboxSettings {
+    pub poll_interval_ms: u64,
+    pub batch_size: i64,
+    pub max_attempts: i64, // then dead
+    pub backoff_secs: i64, // doubled with each attempt
+    pub max_backoff_secs: i64,
+    pub lease_secs: i64, // claimed messages not recorded within this are due again
+}
+
+impl Default for OutboxSettings {
+    fn default() -> Self {
+        Self {
+            poll_interval_ms: 1000,
+            batch_size: 50,
+            max_attempts: 10,
+            backoff_secs: 5,
+            max_backoff_secs: 3600,
+            lease_secs: 300,
+        }
+    }
+}
+
+impl OutboxSettings {
+    pub fn backoff(&self, attempts: i64) -> i64 {
+        let exp = self.backoff_secs.saturating_mul(1 << (attempts - 1).clamp(0, 30));
+        exp.min(self.max_backoff_secs)
+    }
+
+    // a batch is delivered one message after the other under one lease, each gets its share of it
+    pub fn delivery_timeout(&self) -> Duration {
+        Duration::from_secs((self.lease_secs / self.batch_size.max(1)).max(1) as u64)
+    }
+}
+
+pub struct OutboxDispatcher<#[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend> {
+    db: DbMain<B>,
+    clock: Arc<dyn Clock>,
+    settings: OutboxSettings,
+    handlers: HashMap<String, Arc<dyn OutboxHandler>>,
+    notify: Arc<Notify>,
+}
+
+impl<B: Backend> OutboxDispatcher<B> {
+    pub fn new(db: DbMain<B>, clock: Arc<dyn Clock>, settings: OutboxSettings) -> Self {
+        Self {
+            db,
+            clock,
+            settings,
+            handlers: HashMap::new(),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn register(mut self, topic: &str, handler: Arc<dyn OutboxHandler>) -> Self {
+        self.handlers.insert(topic.into(), handler);
+        self
+    }
+
+    // wakes the dispatcher before the poll interval, e.g. from WriteTxn::after_commit
+    pub fn notifier(&self) -> Arc<Notify> {
+        Arc::clone(&self.notify)
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match self.dispatch_once().await {
+                    // there may be more
+                    Ok(n) if n as i64 >= self.settings.batch_size => continue,
+                    Ok(_) => {},
+                    Err(e) => log::error!("Outbox dispatch failed: {e:?}"),
+                }
+                tokio::select! {
+                    _ = self.notify.notified() => {},
+                    _ = tokio::time::sleep(Duration::from_millis(self.settings.poll_interval_ms)) => {},
+                }
+            }
+        })
+    }
+
+    // returns the number of messages attempted. A result that can't be recorded is logged and the
+    // message is due again once its lease runs out, the rest of the batch still goes out
+    pub async fn dispatch_once(&self) -> anyhow::Result<usize> {
+        let due = self.claim().await?;
+        for msg in due.iter() {
+            let res = match self.handlers.get(&msg.topic) {
+                Some(handler) => handler.deliver(msg).await,
+                None => Err(anyhow::anyhow!("no outbox handler for topic {}", msg.topic)),
+            };
+            if let Err(e) = self.record(msg, res).await {
+                log::error!("Outbox message {} ({}) result not recorded: {e:?}", msg.id, msg.topic);
+                counter!("outbox_unrecorded", 1, "topic" => msg.topic.clone());
+            }
+        }
+        Ok(due.len())
+    }
+
+    // takes the due messages out of the queue for lease_secs, other dispatchers skip them meanwhile
+    async fn claim(&self) -> anyhow::Result<Vec<OutboxMessage>> {
+        let now = self.clock.utcnow();
+        let querystr = format!("UPDATE outbox SET status = $1, next_attempt = $2 \
+            WHERE status IN ($3, $1) AND next_attempt <= $4 AND id IN \
+            (SELECT id FROM outbox WHERE status IN ($3, $1) AND next_attempt <= $4 ORDER BY id LIMIT $5{}) \
+            RETURNING *", B::by_backend("", " FOR UPDATE SKIP LOCKED"));
+        let mut txn = self.db.newtx_write().await?;
+        let rows = sqlx::query_with(&querystr, Args::<B>::new()
+                .bind(OUTBOX_IN_FLIGHT)
+                .bind(now + self.settings.lease_secs)
+                .bind(OUTBOX_PENDING)
+                .bind(now)
+                .bind(self.settings.batch_size))
+            .fetch_all(txn.conn())
+            .await?;
+        txn.commit().await?;
+        let mut due = rows.iter().map(OutboxMessage::from_row::<B>).collect::<Result<Vec<OutboxMessage>, sqlx::Error>>()?;
+        due.sort_by_key(|it| it.id);
+        Ok(due)
+    }
+
+    // only while the claim is still ours, after the lease another dispatcher may have the message
+    async fn record(&self, msg: &OutboxMessage, res: anyhow::Result<()>) -> anyhow::Result<()> {
+        let now = self.clock.utcnow();
+        let attempts = msg.attempts + 1;
+        let mut txn = self.db.newtx_write().await?;
+        let updated = match res {
+            Ok(()) => {
+                counter!("outbox_delivered", 1, "topic" => msg.topic.clone());
+                sqlx::query_with("UPDATE outbox SET status = $1, attempts = $2, last_error = NULL \
+                        WHERE id = $3 AND status = $4 AND next_attempt = $5", Args::<B>::new()
+                        .bind(OUTBOX_DELIVERED)
+                        .bind(attempts)
+                        .bind(msg.id)
+                        .bind(OUTBOX_IN_FLIGHT)
+                        .bind(msg.lease))
+                    .execute(txn.conn())
+                    .await?
+            },
+            Err(e) => {
+                let status = if attempts >= self.settings.max_attempts { OUTBOX_DEAD } else { OUTBOX_PENDING };
+                if status == OUTBOX_DEAD {
+                    log::error!("Outbox message {} ({}) dead after {attempts} attempts: {e:?}", msg.id, msg.topic);
+                    counter!("outbox_dead", 1, "topic" => msg.topic.clone());
+                } else {
+                    log::warn!("Outbox message {} ({}) attempt {attempts} failed: {e:?}", msg.id, msg.topic);
+                    counter!("outbox_failed", 1, "topic" => msg.topic.clone());
+                }
+                sqlx::query_with("UPDATE outbox SET status = $1, attempts = $2, next_attempt = $3, last_error = $4 \
+                        WHERE id = $5 AND status = $6 AND next_attempt = $7", Args::<B>::new()
+                        .bind(status)
+                        .bind(attempts)
+                        .bind(now + self.settings.backoff(attempts))
+                        .bind(format!("{e:?}"))
+                        .bind(msg.id)
+                        .bind(OUTBOX_IN_FLIGHT)
+                        .bind(msg.lease))
+                    .execute(txn.conn())
+                    .await?
+            }
+        };
+        if B::rows_affected(&updated) == 0 {
+            log::warn!("Outbox message {} ({}) lease ran out before its result was recorded", msg.id, msg.topic);
+        }
+        txn.commit().await?;
+        Ok(())
+    }
+}
+
+// puts dead messages back in the queue, for after the cause has been fixed
+pub async fn outbox_retry_dead<B: Backend>(db: &mut DbWriteTxn<'_, B>, now: Instant) -> anyhow::Result<u64> {
+    let res = sqlx::query_with("UPDATE outbox SET status = $1, attempts = 0, next_attempt = $2 WHERE status = $3", Args::<B>::new()
+            .bind(OUTBOX_PENDING)
+            .bind(now)
+            .bind(OUTBOX_DEAD))
+        .execute(db.conn())
+        .await?;
+    Ok(B::rows_affected(&res))
+}
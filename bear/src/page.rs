@@ -0,0 +1,139 @@
+use std::future::{Ready, ready};
+use actix_web::{Error, FromRequest, HttpRequest, web};
+use actix_web::dev::Payload;
+use serde::{Deserialize, Serialize};
+use crate::errors::{AnyHandlerError, ApiError};
+
+// Keyset pagination. Instead of OFFSET (which scans all skipped rows) the next page starts after the
+// last row of the previous one: `WHERE sort < last OR (sort = last AND id < last_id)`. The position
+// is handed to the client as an opaque bs58 cursor, so it can change format without breaking clients.
+//
+//   async fn list(mut txn: ReadTxn<'_>, page: PageParams) -> Result<web::Json<Page<Order>>, AnyHandlerError> {
+//       let sel = Select::<Order>::new().filter(col("status").eq("open"));
+//       Ok(web::Json(fetch_page(txn.get(), sel, "created", Dir::Desc, &page, |it| (it.created.into(), it.id.to_string())).await?))
+//   }
+//
+// The types here are the same for every backend, fetch_page is in the backend's query module.
+// Page<T> derives ts_rs::TS, utoipa can't describe generic types, so for register_types! use page_type!.
+
+pub const DEFAULT_PAGE_LIMIT: i64 = 20;
+pub const MAX_PAGE_LIMIT: i64 = 100;
+
+#[derive(Debug, Serialize, ts_rs::TS)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    // pass as ?cursor= to get the next page, none on the last page
+    pub next: Option<String>,
+}
+
+/// Concrete Page<T> for the api spec, same json. Needs serde, ts_rs and utoipa in the app, like register_types!:
+///
+///   page_type!(OrderPage, Order);
+///   register_types!(Order, OrderPage);
+///   ...
+///   Ok(web::Json(OrderPage::from(fetch_page(...).await?)))
+#[macro_export]
+macro_rules! page_type {
+    ($name:ident, $t:ty) => {
+        #[derive(Debug, serde::Serialize, ts_rs::TS, utoipa::ToSchema)]
+        pub struct $name {
+            pub items: Vec<$t>,
+            // pass as ?cursor= to get the next page, none on the last page
+            pub next: Option<String>,
+        }
+
+        impl From<$crate::page::Page<$t>> for $name {
+            fn from(page: $crate::page::Page<$t>) -> Self {
+                $name { items: page.items, next: page.next }
+            }
+        }
+    }
+}
+
+/// Value of the sort column, as stored in a cursor.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum KeyValue {
+    Int(i64),
+    Real(f64),
+    Text(String),
+}
+
+impl From<i64> for KeyValue {
+    fn from(v: i64) -> Self {
+        KeyValue::Int(v)
+    }
+}
+
+impl From<f64> for KeyValue {
+    fn from(v: f64) -> Self {
+        KeyValue::Real(v)
+    }
+}
+
+impl From<String> for KeyValue {
+    fn from(v: String) -> Self {
+        KeyValue::Text(v)
+    }
+}
+
+impl From<&str> for KeyValue {
+    fn from(v: &str) -> Self {
+        KeyValue::Text(v.into())
+    }
+}
+
+/// Decoded cursor: sort key and id of the last row of the previous page.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub key: KeyValue,
+    pub id: String,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        bs58::encode(serde_json::to_vec(self).unwrap()).into_string()
+    }
+
+    pub fn decode(s: &str) -> anyhow::Result<Cursor> {
+        let bytes = bs58::decode(s).into_vec()
+            .map_err(|_| ApiError::Any("cursor.invalid".into()))?;
+        Ok(serde_json::from_slice(&bytes)
+            .map_err(|_| ApiError::Any("cursor.invalid".into()))?)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+/// Extracts `?cursor=...&limit=...`, limit defaults to DEFAULT_PAGE_LIMIT and is capped at MAX_PAGE_LIMIT.
+#[derive(Debug, Clone)]
+pub struct PageParams {
+    pub cursor: Option<Cursor>,
+    pub limit: i64,
+}
+
+impl PageParams {
+    pub fn parse(query: &str) -> anyhow::Result<PageParams> {
+        let q = web::Query::<PageQuery>::from_query(query)
+            .map_err(|_| ApiError::InvalidInput)?
+            .into_inner();
+        let cursor = match q.cursor.as_deref() {
+            None | Some("") => None,
+            Some(it) => Some(Cursor::decode(it)?),
+        };
+        let limit = q.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT);
+        Ok(PageParams { cursor, limit })
+    }
+}
+
+impl FromRequest for PageParams {
+    type Error = Error;
+    type Future = Ready<Result<PageParams, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(PageParams::parse(req.query_string()).map_err(|e| AnyHandlerError::from(e).into()))
+    }
+}
@@ -0,0 +1,369 @@
+use std::marker::PhantomData;
+use sqlx::{Arguments, FromRow};
+use crate::backend::{Args, Backend, DbArguments, DbRow, Param};
+#[cfg(feature = "sqlite")]
+use crate::backend::Sqlite;
+use crate::db::{checked_column, count_of, DbTxn, TableMetadata};
+use crate::table::placeholder;
+use crate::page::{Cursor, KeyValue, Page, PageParams};
+
+// Query builder for list endpoints, so they don't hand write sql:
+//
+//   let rows: Vec<Order> = Select::<Order>::new()
+//       .filter(col("status").eq("open").or(col("status").eq("paid")))
+//       .filter(col("amount").between(100, 500))
+//       .order_by("created", Dir::Desc)
+//       .limit(20)
+//       .fetch_all(txn.get()).await?;   // txn: ReadTxn
+//
+// Filters are ANDed, soft deleted rows are left out unless with_deleted() is called. Column names are
+// checked against T::columns() when the sql is built, values are bound as numbered arguments in the order
+// they appear in the sql. Select and Cond are generic over the backend like DbMain, a postgres app writes
+// `Select::<Order, Postgres>::new()`.
+
+// appends one value to the arguments, boxed so conditions can hold values of different types
+type Binder<'q, B> = Box<dyn FnOnce(&mut DbArguments<'q, B>) + Send + 'q>;
+
+fn binder<'q, V, B: Backend>(v: V) -> Binder<'q, B>
+    where V: 'q + Send + sqlx::Encode<'q, B::Db> + sqlx::Type<B::Db>
+{
+    Box::new(move |args: &mut DbArguments<'q, B>| args.add(v))
+}
+
+// for the values bear binds itself, the cursor keys
+fn param_binder<'q, V: Param<'q>, B: Backend>(v: V) -> Binder<'q, B> {
+    Box::new(move |args: &mut DbArguments<'q, B>| B::add(args, v))
+}
+
+// arguments plus how many there are, for numbering the placeholders
+struct Bound<'q, B: Backend> {
+    args: DbArguments<'q, B>,
+    n: usize,
+}
+
+impl<'q, B: Backend> Bound<'q, B> {
+    fn new() -> Self {
+        Bound { args: DbArguments::<B>::default(), n: 0 }
+    }
+
+    // binds the value and returns its placeholder
+    fn push(&mut self, v: Binder<'q, B>) -> String {
+        v(&mut self.args);
+        self.n += 1;
+        placeholder(self.n)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Like,
+}
+
+impl Op {
+    fn sql(&self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::Ne => "<>",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Like => "LIKE",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dir {
+    Asc,
+    Desc,
+}
+
+pub enum Cond<'q, #[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend> {
+    Cmp(&'q str, Op, Binder<'q, B>),
+    In(&'q str, Vec<Binder<'q, B>>),
+    Between(&'q str, Binder<'q, B>, Binder<'q, B>),
+    IsNull(&'q str),
+    NotNull(&'q str),
+    And(Vec<Cond<'q, B>>),
+    Or(Vec<Cond<'q, B>>),
+}
+
+/// Starts a condition on a column, e.g. `col("amount").ge(100)`
+pub fn col(name: &str) -> Col<'_> {
+    Col(name)
+}
+
+pub struct Col<'q>(&'q str);
+
+impl<'q> Col<'q> {
+    pub fn cmp<V, B: Backend>(self, op: Op, v: V) -> Cond<'q, B>
+        where V: 'q + Send + sqlx::Encode<'q, B::Db> + sqlx::Type<B::Db>
+    {
+        Cond::Cmp(self.0, op, binder::<V, B>(v))
+    }
+
+    pub fn eq<V, B: Backend>(self, v: V) -> Cond<'q, B> where V: 'q + Send + sqlx::Encode<'q, B::Db> + sqlx::Type<B::Db> { self.cmp(Op::Eq, v) }
+    pub fn ne<V, B: Backend>(self, v: V) -> Cond<'q, B> where V: 'q + Send + sqlx::Encode<'q, B::Db> + sqlx::Type<B::Db> { self.cmp(Op::Ne, v) }
+    pub fn lt<V, B: Backend>(self, v: V) -> Cond<'q, B> where V: 'q + Send + sqlx::Encode<'q, B::Db> + sqlx::Type<B::Db> { self.cmp(Op::Lt, v) }
+    pub fn le<V, B: Backend>(self, v: V) -> Cond<'q, B> where V: 'q + Send + sqlx::Encode<'q, B::Db> + sqlx::Type<B::Db> { self.cmp(Op::Le, v) }
+    pub fn gt<V, B: Backend>(self, v: V) -> Cond<'q, B> where V: 'q + Send + sqlx::Encode<'q, B::Db> + sqlx::Type<B::Db> { self.cmp(Op::Gt, v) }
+    pub fn ge<V, B: Backend>(self, v: V) -> Cond<'q, B> where V: 'q + Send + sqlx::Encode<'q, B::Db> + sqlx::Type<B::Db> { self.cmp(Op::Ge, v) }
+    pub fn like<V, B: Backend>(self, v: V) -> Cond<'q, B> where V: 'q + Send + sqlx::Encode<'q, B::Db> + sqlx::Type<B::Db> { self.cmp(Op::Like, v) }
+
+    // inclusive on both ends
+    pub fn between<V, B: Backend>(self, from: V, to: V) -> Cond<'q, B>
+        where V: 'q + Send + sqlx::Encode<'q, B::Db> + sqlx::Type<B::Db>
+    {
+        Cond::Between(self.0, binder::<V, B>(from), binder::<V, B>(to))
+    }
+
+    // an empty list matches nothing
+    pub fn is_in<V, I, B: Backend>(self, vs: I) -> Cond<'q, B>
+        where V: 'q + Send + sqlx::Encode<'q, B::Db> + sqlx::Type<B::Db>,
+              I: IntoIterator<Item = V>
+    {
+        Cond::In(self.0, vs.into_iter().map(binder::<V, B>).collect())
+    }
+
+    pub fn is_null<B: Backend>(self) -> Cond<'q, B> {
+        Cond::IsNull(self.0)
+    }
+
+    pub fn not_null<B: Backend>(self) -> Cond<'q, B> {
+        Cond::NotNull(self.0)
+    }
+}
+
+impl<'q, B: Backend> Cond<'q, B> {
+    pub fn all(conds: Vec<Cond<'q, B>>) -> Self {
+        Cond::And(conds)
+    }
+
+    pub fn any(conds: Vec<Cond<'q, B>>) -> Self {
+        Cond::Or(conds)
+    }
+
+    pub fn and(self, other: Cond<'q, B>) -> Self {
+        match self {
+            Cond::And(mut conds) => { conds.push(other); Cond::And(conds) },
+            it => Cond::And(vec![it, other]),
+        }
+    }
+
+    pub fn or(self, other: Cond<'q, B>) -> Self {
+        match self {
+            Cond::Or(mut conds) => { conds.push(other); Cond::Or(conds) },
+            it => Cond::Or(vec![it, other]),
+        }
+    }
+
+    // writes sql and binds values in the same pass so they line up
+    fn render<T: TableMetadata>(self, sql: &mut String, args: &mut Bound<'q, B>) -> anyhow::Result<()> {
+        match self {
+            Cond::Cmp(c, op, v) => {
+                let c = checked_column::<T>(c)?;
+                sql.push_str(&format!("{c} {} {}", op.sql(), args.push(v)));
+            },
+            Cond::In(c, vs) => {
+                let c = checked_column::<T>(c)?;
+                if vs.is_empty() {
+                    sql.push_str("1 = 0");
+                } else {
+                    let ps = vs.into_iter().map(|v| args.push(v)).collect::<Vec<String>>();
+                    sql.push_str(&format!("{c} IN ({})", ps.join(", ")));
+                }
+            },
+            Cond::Between(c, from, to) => {
+                let c = checked_column::<T>(c)?;
+                sql.push_str(&format!("{c} BETWEEN {} AND {}", args.push(from), args.push(to)));
+            },
+            Cond::IsNull(c) => sql.push_str(&format!("{} IS NULL", checked_column::<T>(c)?)),
+            Cond::NotNull(c) => sql.push_str(&format!("{} IS NOT NULL", checked_column::<T>(c)?)),
+            Cond::And(conds) => Self::render_group::<T>(conds, " AND ", "1 = 1", sql, args)?,
+            Cond::Or(conds) => Self::render_group::<T>(conds, " OR ", "1 = 0", sql, args)?,
+        }
+        Ok(())
+    }
+
+    fn render_group<T: TableMetadata>(conds: Vec<Cond<'q, B>>, sep: &str, empty: &str, sql: &mut String, args: &mut Bound<'q, B>) -> anyhow::Result<()> {
+        if conds.is_empty() {
+            sql.push_str(empty);
+            return Ok(())
+        }
+        sql.push('(');
+        for (i, cond) in conds.into_iter().enumerate() {
+            if i > 0 { sql.push_str(sep) }
+            cond.render::<T>(sql, args)?;
+        }
+        sql.push(')');
+        Ok(())
+    }
+}
+
+pub struct Select<'q, T, #[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend> {
+    filters: Vec<Cond<'q, B>>,
+    order: Vec<(&'q str, Dir)>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    with_deleted: bool,
+    _t: PhantomData<T>,
+}
+
+impl<'q, T, B: Backend> Default for Select<'q, T, B> {
+    fn default() -> Self {
+        Self { filters: vec![], order: vec![], limit: None, offset: None, with_deleted: false, _t: PhantomData }
+    }
+}
+
+impl<'q, T, B: Backend> Select<'q, T, B>
+    where T: TableMetadata + for<'r> FromRow<'r, DbRow<B>> + Send + Unpin
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn filter(mut self, cond: Cond<'q, B>) -> Self {
+        self.filters.push(cond);
+        self
+    }
+
+    // include soft deleted rows
+    pub fn with_deleted(mut self) -> Self {
+        self.with_deleted = true;
+        self
+    }
+
+    pub fn order_by(mut self, col: &'q str, dir: Dir) -> Self {
+        self.order.push((col, dir));
+        self
+    }
+
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: i64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    // `what` is the select list, only the WHERE part takes arguments
+    fn build(mut self, what: &str, paged: bool) -> anyhow::Result<(String, DbArguments<'q, B>)> {
+        let mut sql = format!("SELECT {what} FROM {}", T::table_name());
+        let mut args = Bound::new();
+
+        if let (false, Some(dcol)) = (self.with_deleted, T::deleted_column()) {
+            self.filters.push(col(dcol).is_null());
+        }
+
+        if !self.filters.is_empty() {
+            sql.push_str(" WHERE ");
+            Cond::And(self.filters).render::<T>(&mut sql, &mut args)?;
+        }
+        if !paged { return Ok((sql, args.args)) }
+
+        if !self.order.is_empty() {
+            let order = self.order.iter()
+                .map(|(c, dir)| checked_column::<T>(c).map(|c| match dir {
+                    Dir::Asc => format!("{c} ASC"),
+                    Dir::Desc => format!("{c} DESC"),
+                }))
+                .collect::<anyhow::Result<Vec<String>>>()?;
+            sql.push_str(&format!(" ORDER BY {}", order.join(", ")));
+        }
+        // sqlite needs a LIMIT for OFFSET
+        if self.limit.is_some() || self.offset.is_some() {
+            sql.push_str(&format!(" LIMIT {}", self.limit.map(|it| it.to_string()).unwrap_or(B::NO_LIMIT.into())));
+        }
+        if let Some(offset) = self.offset {
+            sql.push_str(&format!(" OFFSET {offset}"));
+        }
+        Ok((sql, args.args))
+    }
+
+    pub fn to_sql(self) -> anyhow::Result<String> {
+        Ok(self.build("*", true)?.0)
+    }
+
+    pub async fn fetch_all(self, db: &mut DbTxn<'_, B>) -> anyhow::Result<Vec<T>> {
+        let (sql, args) = self.build("*", true)?;
+        Ok(sqlx::query_as_with(&sql, Args::<B>::from_arguments(args))
+            .fetch_all(db.conn()).await?)
+    }
+
+    pub async fn fetch_optional(self, db: &mut DbTxn<'_, B>) -> anyhow::Result<Option<T>> {
+        let (sql, args) = self.limit(1).build("*", true)?;
+        Ok(sqlx::query_as_with(&sql, Args::<B>::from_arguments(args))
+            .fetch_optional(db.conn()).await?)
+    }
+
+    // ignores order, limit and offset
+    pub async fn count(self, db: &mut DbTxn<'_, B>) -> anyhow::Result<i64> {
+        let (sql, args) = self.build("COUNT(*) AS n", false)?;
+        let row = sqlx::query_with(&sql, Args::<B>::from_arguments(args))
+            .fetch_one(db.conn()).await?;
+        count_of::<B>(&row)
+    }
+}
+
+// .- keyset pagination, see page.rs -.
+
+fn key_cond<'q, B: Backend>(c: &'q str, op: Op, key: KeyValue) -> Cond<'q, B> {
+    match key {
+        KeyValue::Int(v) => Cond::Cmp(c, op, param_binder::<_, B>(v)),
+        KeyValue::Real(v) => Cond::Cmp(c, op, param_binder::<_, B>(v)),
+        KeyValue::Text(v) => Cond::Cmp(c, op, param_binder::<_, B>(v)),
+    }
+}
+
+// the rows after the cursor in the given order, the id breaks ties between equal sort keys
+fn after_cursor<'q, B: Backend>(sort: &'q str, idcol: &'q str, dir: Dir, cursor: Cursor) -> Cond<'q, B> {
+    let op = match dir {
+        Dir::Asc => Op::Gt,
+        Dir::Desc => Op::Lt,
+    };
+    if sort == idcol {
+        return Cond::Cmp(idcol, op, param_binder::<_, B>(cursor.id))
+    }
+    key_cond(sort, op, cursor.key.clone())
+        .or(key_cond(sort, Op::Eq, cursor.key).and(Cond::Cmp(idcol, op, param_binder::<_, B>(cursor.id))))
+}
+
+/// Fetches one page of `select` ordered by `sort` (then the id). `key` returns the sort value and id
+/// of a row, it is used to build the next cursor. The select should have no order or limit of its own.
+pub async fn fetch_page<'q, T, K, B: Backend>(db: &mut DbTxn<'_, B>, select: Select<'q, T, B>, sort: &'q str, dir: Dir, params: &PageParams, key: K) -> anyhow::Result<Page<T>>
+    where T: TableMetadata + for<'r> FromRow<'r, DbRow<B>> + Send + Unpin,
+          K: Fn(&T) -> (KeyValue, String)
+{
+    let idcol = T::id_column();
+    let mut select = select;
+    if let Some(cursor) = params.cursor.clone() {
+        select = select.filter(after_cursor(sort, idcol, dir, cursor));
+    }
+    if sort != idcol {
+        select = select.order_by(sort, dir);
+    }
+    // one extra row tells whether there is a next page
+    let mut items = select.order_by(idcol, dir)
+        .limit(params.limit + 1)
+        .fetch_all(db).await?;
+
+    let next = if items.len() as i64 > params.limit {
+        items.truncate(params.limit as usize);
+        items.last().map(|it| {
+            let (key, id) = key(it);
+            Cursor { key, id }.encode()
+        })
+    } else {
+        None
+    };
+    Ok(Page { items, next })
+}
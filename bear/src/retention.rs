@@ -0,0 +1,93 @@
+use std::time::Duration;
+use actix_web::web;
+use metrics::counter;
+use tokio::task::JoinHandle;
+use crate::backend::{Args, Backend};
+use crate::db::{DbMain, TableMetadata};
+use crate::errors::ApiError;
+use crate::interface::AppContainer;
+
+// Hard deletes soft deleted rows (see TableMetadata::deleted_column) once they are older than the
+// period configured for their table. Time comes from AppContainer::utcnow, so tests can drive it
+// with MockClock and call run_once.
+//
+//   Retention::new(db.clone(), objs.clone())
+//       .keep::<Order>(90 * 24 * 3600)?
+//       .spawn(Duration::from_secs(3600));
+
+// rows per delete statement, the writer is released in between
+const RETENTION_BATCH: i64 = 500;
+
+#[derive(Debug, Clone)]
+pub struct RetentionRule {
+    pub table: &'static str,
+    pub id_column: &'static str,
+    pub deleted_column: &'static str,
+    pub keep_secs: i64,
+}
+
+pub struct Retention<AC: AppContainer> {
+    db: DbMain<AC::B>,
+    objs: web::Data<AC>,
+    rules: Vec<RetentionRule>,
+}
+
+impl<AC: AppContainer + 'static> Retention<AC> {
+    pub fn new(db: DbMain<AC::B>, objs: web::Data<AC>) -> Self {
+        Self { db, objs, rules: vec![] }
+    }
+
+    // soft deleted rows of T are removed `keep_secs` after their deletion
+    pub fn keep<T: TableMetadata>(mut self, keep_secs: i64) -> anyhow::Result<Self> {
+        let deleted_column = T::deleted_column()
+            .ok_or_else(|| ApiError::InvalidState(format!("{}.deleted.missing", T::table_name())))?;
+        self.rules.push(RetentionRule { table: T::table_name(), id_column: T::id_column(), deleted_column, keep_secs });
+        Ok(self)
+    }
+
+    pub fn rules(&self) -> &[RetentionRule] {
+        &self.rules
+    }
+
+    pub fn spawn(self, every: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.run_once().await {
+                    log::error!("Retention failed: {e:?}");
+                }
+                tokio::time::sleep(every).await;
+            }
+        })
+    }
+
+    // returns the number of rows deleted
+    pub async fn run_once(&self) -> anyhow::Result<u64> {
+        let now = self.objs.utcnow();
+        let mut total = 0;
+        for rule in self.rules.iter() {
+            let deleted = self.purge(rule, now - rule.keep_secs).await?;
+            if deleted > 0 {
+                log::info!("Retention removed {deleted} rows from {}", rule.table);
+                counter!("retention_deleted", deleted, "table" => rule.table);
+            }
+            total += deleted;
+        }
+        Ok(total)
+    }
+
+    async fn purge(&self, rule: &RetentionRule, cutoff: i64) -> anyhow::Result<u64> {
+        let RetentionRule { table, id_column, deleted_column, .. } = rule;
+        let querystr = format!("DELETE FROM {table} WHERE {id_column} IN \
+            (SELECT {id_column} FROM {table} WHERE {deleted_column} IS NOT NULL AND {deleted_column} < $1 LIMIT $2)");
+        let mut total = 0;
+        loop {
+            let mut txn = self.db.newtx_write().await?;
+            let res = sqlx::query_with(&querystr, Args::<AC::B>::new().bind(cutoff).bind(RETENTION_BATCH))
+                .execute(txn.conn())
+                .await?;
+            txn.commit().await?;
+            total += AC::B::rows_affected(&res);
+            if (AC::B::rows_affected(&res) as i64) < RETENTION_BATCH { return Ok(total) }
+        }
+    }
+}
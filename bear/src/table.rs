@@ -0,0 +1,211 @@
+use sqlx::database::HasArguments;
+use sqlx::Database;
+use crate::errors::ApiError;
+
+// Tables, rows and typed ids, db re-exports them. BearTable rows and typed ids work with every backend.
+
+pub trait TableMetadata {
+    fn table_name() -> &'static str;
+    // every column, the generic helpers write whole rows with them and only let these names into sql
+    fn columns() -> &'static [&'static str];
+    fn id_column() -> &'static str { "id" }
+    // integer column bumped on every versioned update, opts the table into optimistic locking
+    fn version_column() -> Option<&'static str> { None }
+    // nullable timestamp, when set the row is soft deleted and the generic finders skip it
+    fn deleted_column() -> Option<&'static str> { None }
+}
+
+/// Rows the generic helpers can write on the backend DB. Derived by BearTable or generated by
+/// `row_reader!(Cls in "table" of [...] on Backend)`, which also declares the columns, so reading and writing share one field list.
+pub trait TableRow<DB: Database>: TableMetadata {
+    // binds the values of columns() in order
+    fn bind_columns<'q>(&'q self, args: &mut <DB as HasArguments<'q>>::Arguments);
+    // lets upsert audit the values it overwrites
+    fn id_value(&self) -> Option<String> { None }
+    // binds the id as its column's type, for looking up the row of id_value()
+    fn bind_id<'q>(&'q self, _args: &mut <DB as HasArguments<'q>>::Arguments) {}
+    // an autoincrement id that is still None, insert leaves the column out so the database assigns it
+    fn id_unset(&self) -> bool { false }
+    // binds the values of columns() but the id in order, only called when id_unset()
+    fn bind_columns_without_id<'q>(&'q self, _args: &mut <DB as HasArguments<'q>>::Arguments) {}
+}
+
+// Column names are interpolated into sql, so they must be one of T::columns(). They often come from the
+// request (sort, filter), so an unknown one is the client's error, see ApiError::UnknownColumn.
+pub fn checked_column<T: TableMetadata>(col: &str) -> anyhow::Result<&str> {
+    if !T::columns().contains(&col) {
+        log::error!("Unknown column {} for table {}", col, T::table_name());
+        return Err(ApiError::UnknownColumn(format!("{}.{}", T::table_name(), col)).into())
+    }
+    Ok(col)
+}
+
+// 1 based positional parameter, sqlx takes `$n` on sqlite too
+pub fn placeholder(n: usize) -> String {
+    format!("${n}")
+}
+
+// `$from, $from+1, ...` for `count` values
+pub fn placeholders(from: usize, count: usize) -> String {
+    (from..from + count).map(placeholder).collect::<Vec<String>>().join(", ")
+}
+
+// Typed IDs
+#[macro_export]
+macro_rules! typed_id {
+    ($x:ident) => {
+        #[derive(sqlx::Type, Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, Eq, Hash, Default)]
+        #[sqlx(transparent)]
+        pub struct $x(pub String);
+
+        impl From<String> for $x {
+            fn from(s: String) -> Self {
+                Self(s)
+            }
+        }
+
+        impl From<&str> for $x {
+            fn from(s: &str) -> Self {
+                Self(String::from(s))
+            }
+        }
+
+        $crate::typed_id!(@common $x);
+    };
+
+    // typed_id!(OrderId, "ord") gives ids like ord_<bs58>, the prefix is checked whenever an id comes in
+    // from json or the db. Add `sortable` for ulid-like ids that sort by creation time.
+    ($x:ident, $prefix:literal) => {
+        $crate::typed_id!(@prefixed $x, $prefix, $crate::utils::gentoken::<String>());
+    };
+
+    ($x:ident, $prefix:literal, sortable) => {
+        $crate::typed_id!(@prefixed $x, $prefix, $crate::utils::gen_sortable_token());
+    };
+
+    (@prefixed $x:ident, $prefix:literal, $gen:expr) => {
+        #[derive(Debug, Clone, PartialEq, serde::Serialize, Eq, Hash)]
+        pub struct $x(String);
+
+        impl $x {
+            pub const PREFIX: &'static str = $prefix;
+
+            pub fn new() -> Self {
+                Self(format!("{}_{}", $prefix, $gen))
+            }
+
+            pub fn parse(s: &str) -> anyhow::Result<Self> {
+                let valid = s.strip_prefix($prefix)
+                    .and_then(|it| it.strip_prefix('_'))
+                    .is_some_and(|it| !it.is_empty() && it.chars().all(|c| c.is_ascii_alphanumeric()));
+                if !valid {
+                    return Err($crate::errors::ApiError::Any(format!("{}.id.invalid", $prefix)).into())
+                }
+                Ok(Self(String::from(s)))
+            }
+        }
+
+        impl std::str::FromStr for $x {
+            type Err = anyhow::Error;
+
+            fn from_str(s: &str) -> anyhow::Result<Self> {
+                Self::parse(s)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $x {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+                Self::parse(&s).map_err(serde::de::Error::custom)
+            }
+        }
+
+        // the same as String on every backend, with the prefix checked when decoding
+        impl<DB: sqlx::Database> sqlx::Type<DB> for $x where String: sqlx::Type<DB> {
+            fn type_info() -> DB::TypeInfo {
+                <String as sqlx::Type<DB>>::type_info()
+            }
+
+            fn compatible(ty: &DB::TypeInfo) -> bool {
+                <String as sqlx::Type<DB>>::compatible(ty)
+            }
+        }
+
+        impl<'q, DB: sqlx::Database> sqlx::Encode<'q, DB> for $x where String: sqlx::Encode<'q, DB> {
+            fn encode_by_ref(&self, buf: &mut <DB as sqlx::database::HasArguments<'q>>::ArgumentBuffer) -> sqlx::encode::IsNull {
+                <String as sqlx::Encode<'q, DB>>::encode_by_ref(&self.0, buf)
+            }
+        }
+
+        impl<'r, DB: sqlx::Database> sqlx::Decode<'r, DB> for $x where String: sqlx::Decode<'r, DB> {
+            fn decode(value: <DB as sqlx::database::HasValueRef<'r>>::ValueRef) -> Result<Self, sqlx::error::BoxDynError> {
+                let s = <String as sqlx::Decode<'r, DB>>::decode(value)?;
+                Ok(Self::parse(&s)?)
+            }
+        }
+
+        $crate::typed_id!(@common $x);
+    };
+
+    (@common $x:ident) => {
+        impl AsRef<str> for $x {
+            fn as_ref(&self) -> &str {
+                self.0.as_str()
+            }
+        }
+
+        impl std::fmt::Display for $x {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl ts_rs::TS for $x {
+            fn name() -> String {
+                String::from("string")
+            }
+
+            fn dependencies() -> Vec<ts_rs::Dependency> {
+                Vec::new()
+            }
+
+            fn transparent() -> bool { false }
+        }
+    };
+}
+
+// row_reader! only knows the field names, so unlike BearTable it can't be generic over the backend, it
+// names it: `row_reader!(Cls of [...] on bear::backend::Sqlite)`. What it expands to doesn't depend on the
+// features bear is built with.
+#[macro_export]
+macro_rules! row_reader {
+    // also declares the table, see TableRow
+    ($cls:ident in $table:literal of [ $($x:ident),+ ] on $backend:ty) => {
+        $crate::row_reader!($cls of [ $($x),+ ] on $backend);
+
+        impl $crate::table::TableMetadata for $cls {
+            fn table_name() -> &'static str { $table }
+            fn columns() -> &'static [&'static str] { &[ $(stringify!($x)),+ ] }
+        }
+
+        impl $crate::table::TableRow<<$backend as $crate::backend::Backend>::Db> for $cls {
+            fn bind_columns<'q>(&'q self, args: &mut $crate::backend::DbArguments<'q, $backend>) {
+                $(
+                    sqlx::Arguments::add(args, &self.$x);
+                )+
+            }
+        }
+    };
+    ($cls:ident of [ $($x:ident),+ ] on $backend:ty) => {
+        impl<'r> sqlx::FromRow<'r, $crate::backend::DbRow<$backend>> for $cls {
+            fn from_row(row: &'r $crate::backend::DbRow<$backend>) -> Result<Self, sqlx::Error> {
+                use sqlx::Row;
+                Ok(Self {
+                    $(
+                        $x: row.try_get(stringify!($x))?,
+                    )*
+                })
+            }
+        }
+    };
+}
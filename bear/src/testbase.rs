@@ -4,8 +4,11 @@ use std::sync::{Mutex};
 
 use actix_web::dev::ServerHandle;
 
-use crate::db::DbMain;
-use crate::txnmw::Switcharoo;
+use crate::backend::Backend;
+use crate::db::{DbMain, DbWriteTxn, TxnBlockFuture};
+use crate::txnmw::{Switcharoo, TxnHooks};
+#[cfg(feature = "test-support")]
+use crate::txnmw::{TxnLog, TxnState};
 
 static SERVER_HANDLE: Mutex<Option<ServerHandle>> = Mutex::new(None);
 
@@ -24,6 +27,42 @@ pub fn err_expected<T: ToString>(act: Option<T>, exp: &str) -> bool {
     has
 }
 
+// last commit/rollback recorded for the test app, see TxnLog
+#[cfg(feature = "test-support")]
+pub fn last_txn_outcome(log: &TxnLog) -> Option<TxnState> {
+    log.states().into_iter().rev().find(|it| matches!(it, TxnState::Committed | TxnState::RolledBack))
+}
+
+#[cfg(feature = "test-support")]
+fn outcome_expected(log: &TxnLog, exp: TxnState) -> bool {
+    let act = last_txn_outcome(log);
+    let has = act == Some(exp);
+    if !has {
+        log::error!("Expected transaction {:?} but got {:?}, log: {:?}", exp, act, log.states());
+    }
+    has
+}
+
+#[cfg(feature = "test-support")]
+pub fn expect_committed(log: &TxnLog) -> bool {
+    outcome_expected(log, TxnState::Committed)
+}
+
+#[cfg(feature = "test-support")]
+pub fn expect_rolled_back(log: &TxnLog) -> bool {
+    outcome_expected(log, TxnState::RolledBack)
+}
+
+#[cfg(feature = "test-support")]
+pub fn expect_no_txn(log: &TxnLog) -> bool {
+    let states = log.states();
+    let has = !states.contains(&TxnState::Started);
+    if !has {
+        log::error!("Expected no transaction but got log: {:?}", states);
+    }
+    has
+}
+
 pub fn put_server_handle(h: ServerHandle) {
     let mut handle = SERVER_HANDLE.lock().unwrap();
     if handle.is_some() { panic!("leftover") }
@@ -32,31 +71,40 @@ pub fn put_server_handle(h: ServerHandle) {
 
 // kill any old server
 pub async fn kill_server() {
-    let mut oldsvr = SERVER_HANDLE.lock().unwrap();
-    if let Some(ref svr_it) = *oldsvr {
+    // not holding the lock while stopping
+    let oldsvr = SERVER_HANDLE.lock().unwrap().take();
+    if let Some(svr_it) = oldsvr {
         svr_it.stop(true).await;
         //sleep(Duration::from_secs(8)).await;
-        *oldsvr = None
     }
 }
 
 // not returning Result (just panic) for easier handling and checking of errors of the inner block
-pub async fn handler_with_tx<'a, F: Fn(Switcharoo<'a>) -> K, K: Future<Output=R>, R>(db: &DbMain, block: F) -> R {
-    let (txn, txco) = Switcharoo::from_tx(db.newtx_read().await).unwrap().into_tuple();
+pub async fn handler_with_tx<'a, F: Fn(Switcharoo<'a, B>) -> K, K: Future<Output=R>, R, B: Backend>(db: &DbMain<B>, block: F) -> R {
+    let holder = Switcharoo::from_tx(db.newtx_write().await).unwrap();
+    let hooks = holder.for_hooks.clone();
+    let (txn, txco) = holder.into_tuple();
     let res = block(txn).await;
     txco.take().unwrap().commit().await.unwrap();
+    TxnHooks::run_after_commit(&hooks).await;
     res
 }
-/* lifetimes too complex
-pub async fn with_tx<'a, 'b, F, K>(db: &DbMain, block: F) -> anyhow::Result<R>
-    where
-        'a: 'b,
-        F: Fn(&mut DbWriteTxn<'_>) -> K,
-        K: Future<Output=()>
+
+// handler_with_tx recording into `log`, e.g. to check savepoints
+#[cfg(feature = "test-support")]
+pub async fn handler_with_tx_logged<'a, F: Fn(Switcharoo<'a, B>) -> K, K: Future<Output=R>, R, B: Backend>(db: &DbMain<B>, log: &TxnLog, block: F) -> R {
+    let holder = Switcharoo::from_tx_logged(db.newtx_write().await, log).unwrap();
+    let hooks = holder.for_hooks.clone();
+    let (txn, txco) = holder.into_tuple();
+    let res = block(txn).await;
+    txco.take().unwrap().commit().await.unwrap();
+    TxnHooks::run_after_commit(&hooks).await;
+    res
+}
+
+// same as handler_with_tx for code that takes the transaction directly, see DbMain::with_write
+pub async fn with_tx<R, F, B: Backend>(db: &DbMain<B>, block: F) -> R
+    where F: for<'t> FnMut(&'t mut DbWriteTxn<'static, B>) -> TxnBlockFuture<'t, R>
 {
-    let mut txn = db.newtx_write().await.unwrap();
-    let res = block(&mut txn).await;
-    let _ = txn.commit().await.unwrap();
-    Ok(res)
+    db.with_write(block).await.unwrap()
 }
- */
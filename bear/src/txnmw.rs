@@ -1,77 +1,352 @@
-use std::cell::{Cell};
-use std::future::{Ready, ready};
+use std::cell::{Cell, RefCell};
+use std::future::{Future, Ready, ready};
 use std::rc::Rc;
+use std::time::Duration;
 use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
 use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, web};
+use actix_web::http::StatusCode;
+use actix_web::web::{Bytes, BytesMut};
 use anyhow::anyhow;
 use futures_util::future::LocalBoxFuture;
-use crate::db::{DbMain, DbTxn, DbWriteTxn};
+use futures_util::StreamExt;
+use metrics::counter;
+use rand::Rng;
+use crate::audit::{app_clock, AuditClock, AuditContext, AuditEnabled};
+use crate::backend::{Args, Backend};
+#[cfg(feature = "sqlite")]
+use crate::backend::Sqlite;
+use crate::db::{close_txn, DbAnyTxn, DbMain, DbReadTxn, DbWriteTxn, is_busy_error};
 use crate::errors::AnyHandlerError;
 use crate::errors::ApiError;
+use crate::interface::AppContainer;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum TxnState {
     Nonexistent,
     Started,
     Committed,
-    RolledBack
+    RolledBack,
+    SavepointStarted,
+    SavepointReleased,
+    SavepointRolledBack
 }
 
 // .- For keeping track during testing .-
-// #[cfg(test)]  // cant be conditional because we are in a library TODO fix this (and below)
-pub static TXN_LOG: std::sync::Mutex<Vec<TxnState>> = std::sync::Mutex::new(Vec::new());
+// Register as app_data(web::Data::new(log.clone())) on the test app, the middleware and extractors
+// of that app then record into it. Separate apps (parallel tests) get separate logs.
+#[cfg(feature = "test-support")]
+#[derive(Clone, Default)]
+pub struct TxnLog(std::sync::Arc<std::sync::Mutex<Vec<TxnState>>>);
+
+#[cfg(feature = "test-support")]
+impl TxnLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn states(&self) -> Vec<TxnState> {
+        self.0.lock().unwrap().clone()
+    }
+
+    // returns and clears, to check requests one by one
+    pub fn take(&self) -> Vec<TxnState> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+
+    fn push(&self, state: TxnState) {
+        self.0.lock().unwrap().push(state);
+    }
+}
+
+// where the current request logs to, nothing without test-support
+#[derive(Clone, Default)]
+struct TxnLogger(#[cfg(feature = "test-support")] Option<TxnLog>);
+
+impl TxnLogger {
+    #[allow(unused_variables)]
+    fn from_request(req: &HttpRequest) -> Self {
+        #[cfg(feature = "test-support")]
+        return TxnLogger(req.app_data::<web::Data<TxnLog>>().map(|it| it.get_ref().clone()));
+        #[cfg(not(feature = "test-support"))]
+        return TxnLogger();
+    }
+
+    #[allow(unused_variables)]
+    fn log(&self, state: TxnState) {
+        #[cfg(feature = "test-support")]
+        if let Some(ref log) = self.0 {
+            log.push(state);
+        }
+    }
+}
+
+/// Retrying of requests that failed on lock contention (see backend::is_busy). The request body is buffered
+/// and the whole handler is replayed in a fresh transaction, so only use it on scopes whose
+/// handlers have no side effects outside of their transaction.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub backoff_ms: u64, // first delay, doubled with each retry
+    pub max_backoff_ms: u64,
+    pub max_body: usize, // bytes, larger bodies are rejected with BodyTooLarge
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            backoff_ms: 0,
+            max_backoff_ms: 0,
+            max_body: 0,
+        }
+    }
+
+    pub fn new(max_retries: u32) -> Self {
+        RetryPolicy {
+            max_retries,
+            backoff_ms: 20,
+            max_backoff_ms: 1000,
+            max_body: 256 * 1024,
+        }
+    }
+
+    // exponential with jitter so replays of concurrent requests don't collide again
+    pub fn delay(&self, retry: u32) -> Duration {
+        let exp = self.backoff_ms.saturating_mul(1 << retry.saturating_sub(1).min(16));
+        let capped = exp.min(self.max_backoff_ms);
+        Duration::from_millis(capped / 2 + rand::thread_rng().gen_range(0..=capped / 2))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::none()
+    }
+}
+
+/// Status based decision between commit and rollback, set per scope on TxnMidFactory.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum CommitPolicy {
+    #[default]
+    OnSuccess, // rollback on 4xx and 5xx
+    UnlessServerError, // rollback on 5xx only
+}
 
-#[allow(unused)]
-fn log_txn_state(state: TxnState) {
-    // #[cfg(test)]
-    TXN_LOG.lock().unwrap().push(state);
+impl CommitPolicy {
+    pub fn commits(&self, status: StatusCode) -> bool {
+        match self {
+            CommitPolicy::OnSuccess => !(status.is_server_error() || status.is_client_error()),
+            CommitPolicy::UnlessServerError => !status.is_server_error(),
+        }
+    }
+}
+
+/// Overrides the CommitPolicy for one request. Put it into the request extensions (see decide_txn)
+/// or into the response extensions, the latter wins. Useful e.g. for persisting a failed attempt
+/// counter while returning 4xx.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TxnDecision {
+    Commit,
+    Rollback,
+}
+
+pub fn decide_txn(req: &HttpRequest, decision: TxnDecision) {
+    req.extensions_mut().insert(decision);
+}
+
+fn should_commit<Body>(res: &ServiceResponse<Body>, policy: CommitPolicy) -> bool {
+    let decision = res.response().extensions().get::<TxnDecision>().copied()
+        .or_else(|| res.request().extensions().get::<TxnDecision>().copied());
+    match decision {
+        Some(it) => {
+            log::debug!("Transaction decision overridden to {it:?} for {}, status={}", res.request().path(), res.status());
+            it == TxnDecision::Commit
+        },
+        None => policy.commits(res.status())
+    }
 }
 
 /// Transaction Middleware for actix_web and sqlx.
-pub struct TxnMidFactory {
-    pub pool: DbMain
+pub struct TxnMidFactory<#[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend> {
+    pub pool: DbMain<B>,
+    pub retry: RetryPolicy,
+    pub commit: CommitPolicy,
+    pub audit: Option<AuditClock>
 }
 
-impl TxnMidFactory {
-    pub fn new(pool: DbMain) -> TxnMidFactory {
+impl<B: Backend> TxnMidFactory<B> {
+    pub fn new(pool: DbMain<B>) -> TxnMidFactory<B> {
         TxnMidFactory {
-            pool
+            pool,
+            retry: RetryPolicy::none(),
+            commit: CommitPolicy::OnSuccess,
+            audit: None
         }
     }
+
+    pub fn with_commit_policy(mut self, commit: CommitPolicy) -> TxnMidFactory<B> {
+        self.commit = commit;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> TxnMidFactory<B> {
+        self.retry = retry;
+        self
+    }
+
+    // writes through the db helpers go to row_audit, see audit.rs. The time is AC::utcnow, so the app
+    // must have its web::Data<AC> as app_data.
+    pub fn with_audit<AC: AppContainer + 'static>(mut self) -> TxnMidFactory<B> {
+        self.audit = Some(app_clock::<AC>);
+        self
+    }
 }
 
-impl <S, B>Transform<S, ServiceRequest> for TxnMidFactory
+impl <S, Body, B: Backend>Transform<S, ServiceRequest> for TxnMidFactory<B>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S: Service<ServiceRequest, Response = ServiceResponse<Body>, Error = Error> + 'static,
     S::Future: 'static,
-    B: 'static,
+    Body: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<Body>;
     type Error = Error;
-    type Transform = TxnMiddleware<S>;
+    type Transform = TxnMiddleware<S, B>;
     type InitError = ();
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(TxnMiddleware {
             service: Rc::new(service),
-            pool: self.pool.clone()
+            pool: self.pool.clone(),
+            retry: self.retry.clone(),
+            commit: self.commit,
+            audit: self.audit
         }))
     }
 }
 
-pub struct TxnMiddleware<S> {
+pub struct TxnMiddleware<S, #[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend> {
     pub service: Rc<S>,
-    pub pool: DbMain
+    pub pool: DbMain<B>,
+    pub retry: RetryPolicy,
+    pub commit: CommitPolicy,
+    pub audit: Option<AuditClock>
+}
+
+enum Attempt<Body> {
+    Done(Result<ServiceResponse<Body>, Error>),
+    // transaction was rolled back because the database was busy, response is for getting the request back
+    Busy(ServiceResponse<Body>)
+}
+
+fn route_of<Body>(res: &ServiceResponse<Body>) -> String {
+    route_pattern(res.request())
+}
+
+// metrics label of the request, the route pattern rather than the path so ids don't make new series
+fn route_pattern(req: &HttpRequest) -> String {
+    req.match_pattern().unwrap_or_else(|| String::from("unmatched"))
+}
+
+fn handler_busy<Body, B: Backend>(res: &ServiceResponse<Body>) -> bool {
+    res.response().error()
+        .and_then(|e| e.as_error::<AnyHandlerError>())
+        .is_some_and(|e| is_busy_error::<B>(e.inner()))
 }
 
-impl<S, B> Service<ServiceRequest> for TxnMiddleware<S>
+async fn run_in_txn<S, Body, B: Backend>(service: &Rc<S>, req: ServiceRequest, policy: CommitPolicy) -> Attempt<Body>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S: Service<ServiceRequest, Response = ServiceResponse<Body>, Error = Error> + 'static,
+{
+    let txlog = TxnLogger::from_request(req.request());
+    // insert an empty tx container
+    let cont: TxnRcContainer<B> = Rc::new(Cell::new(None));
+    req.extensions_mut().insert(Rc::clone(&cont));
+    // each attempt has its own hooks, a replayed one runs its rollback hooks before the next starts
+    let hooks: TxnHooksRc = Rc::new(RefCell::new(TxnHooks::default()));
+    req.extensions_mut().insert(Rc::clone(&hooks));
+    // left over from a replayed attempt
+    req.extensions_mut().remove::<TxnDecision>();
+    txlog.log(TxnState::Nonexistent);
+    //log::info!("Inserted empty transaction container, calling service");
+
+    let res = match service.call(req).await {
+        Ok(r) if handler_busy::<_, B>(&r) => {
+            counter!("txn_busy", 1, "route" => route_of(&r));
+            if let Some(txn) = cont.take() {
+                txlog.log(TxnState::RolledBack);
+                if let Err(e) = txn.rollback().await {
+                    log::warn!("Rollback after busy error failed: {e:?}");
+                }
+            }
+            TxnHooks::run_after_rollback(&hooks).await;
+            return Attempt::Busy(r)
+        },
+        other => other
+    };
+
+    if let Some(txn) = cont.take() {
+        // service errors (no response) always roll back
+        let committing = matches!(res, Ok(ref r) if should_commit(r, policy));
+        let route = res.as_ref().map_or_else(|_| String::from("unknown"), route_of);
+        txlog.log(if committing { TxnState::Committed } else { TxnState::RolledBack });
+        let closed = close_txn(txn, committing, &route).await;
+
+        // whatever went wrong closing it, the transaction didn't commit
+        if committing && closed.is_ok() {
+            TxnHooks::run_after_commit(&hooks).await;
+        } else {
+            TxnHooks::run_after_rollback(&hooks).await;
+        }
+
+        if let Err(txerr) = closed {
+            let txerr = anyhow::Error::from(txerr);
+            if let Ok(r) = res {
+                if is_busy_error::<B>(&txerr) {
+                    counter!("txn_busy", 1, "route" => route);
+                    // a failed commit leaves nothing behind, safe to replay
+                    return Attempt::Busy(r)
+                }
+                return Attempt::Done(Err(AnyHandlerError::from(anyhow!("SQL error on rollback/commit: {:?}", txerr)).into()))
+            }
+            if let Err(ref e) = res {
+                log::error!("Transaction rollback failed. Also the original handler error was: {e:?}");
+            }
+            return Attempt::Done(Err(AnyHandlerError::from(anyhow!("SQL error on rollback/commit: {:?}", txerr)).into()))
+        }
+        //log::debug!("Transaction closed.");
+    } else {
+        // some handlers don't use transaction (e.g. ReadThenWriteTxn never started), their hooks still run
+        // by what the response would have done to one
+        if matches!(res, Ok(ref r) if should_commit(r, policy)) {
+            TxnHooks::run_after_commit(&hooks).await;
+        } else {
+            TxnHooks::run_after_rollback(&hooks).await;
+        }
+    }
+
+    Attempt::Done(res)
+}
+
+async fn read_body(mut payload: Payload, max_body: usize) -> Result<Bytes, Error> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > max_body {
+            return Err(AnyHandlerError::from(ApiError::BodyTooLarge).into())
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+impl<S, Body, B: Backend> Service<ServiceRequest> for TxnMiddleware<S, B>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<Body>, Error = Error> + 'static,
     S::Future: 'static,
-    B: 'static,
+    Body: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<Body>;
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
@@ -79,45 +354,46 @@ where
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
         let service = Rc::clone(&self.service);
+        let retry = self.retry.clone();
+        let policy = self.commit;
+        if let Some(clock) = self.audit {
+            req.extensions_mut().insert(AuditEnabled(clock));
+        }
 
         Box::pin(async move {
-            // insert an empty tx container
-            let cont: TxnRcContainer = Rc::new(Cell::new(None));
-            req.extensions_mut().insert(Rc::clone(&cont));
-            log_txn_state(TxnState::Nonexistent);
-            //log::info!("Inserted empty transaction container, calling service");
-
-            let res = service.call(req).await;
-            if let Some(txn) = cont.take() {
-                if let Ok(ref r) = res {
-                    let failed = r.status().is_server_error() || r.status().is_client_error();
-                    // log::debug!("Service result is Ok, status={status} failed={failed}");
-                    if !failed {
-                        log_txn_state(TxnState::Committed);
-                        txn.commit().await
-
-                    } else {
-                        log_txn_state(TxnState::RolledBack);
-                        txn.rollback().await
-                    }
-                } else {
-                    //log::debug!("Service result is Error");
-                    log_txn_state(TxnState::RolledBack);
-                    txn.rollback().await
-                }.map_err(|ref txerr| {
-                    if let Err(ref e) = res {
-                        log::error!("Transaction rollback failed. Also the original handler error was: {e:?}");
-                    }
-                    AnyHandlerError::from(anyhow!("SQL error on rollback/commit: {:?}", txerr))
-                })?;
-
-                //log::debug!("Transaction closed.");
+            let (mut http_req, payload) = req.into_parts();
+            // the body can be read only once, buffer it if we may need to replay
+            let (body, mut payload) = if retry.max_retries > 0 {
+                (Some(read_body(payload, retry.max_body).await?), None)
             } else {
-                // some handlers don't use transaction
-                //log::warn!("No transaction in container");
+                (None, Some(payload))
+            };
+            // routing below us consumes the matched path, restore it for replays
+            let path = http_req.match_info().clone();
+            let mut retries = 0;
+
+            loop {
+                let mut req = match body {
+                    Some(ref b) => ServiceRequest::from_parts(http_req, Payload::from(b.clone())),
+                    None => ServiceRequest::from_parts(http_req, payload.take().unwrap_or(Payload::None))
+                };
+                *req.match_info_mut() = path.clone();
+
+                match run_in_txn::<_, _, B>(&service, req, policy).await {
+                    Attempt::Done(res) => return res,
+                    Attempt::Busy(res) => {
+                        if retries >= retry.max_retries {
+                            log::warn!("Database busy, giving up after {retries} retries: {}", res.request().path());
+                            return Err(AnyHandlerError::from(ApiError::LockError).into())
+                        }
+                        retries += 1;
+                        log::info!("Database busy, retry {retries}: {}", res.request().path());
+                        // drop the response so that we hold the only reference to the request
+                        http_req = res.into_parts().0;
+                        tokio::time::sleep(retry.delay(retries)).await;
+                    }
+                }
             }
-
-            Ok(res?)
         })
     }
 }
@@ -131,51 +407,125 @@ where
     Cell demands replacing its content with something else when transferring ownership.
     We just say either tx is there or not.
  */
-pub type TxnRcContainer<'a> = Rc<Cell<Option<DbTxn<'a>>>>;
+pub type TxnRcContainer<'a, #[cfg(feature = "sqlite")] B = Sqlite, #[cfg(not(feature = "sqlite"))] B> = Rc<Cell<Option<DbAnyTxn<'a, B>>>>;
+
+pub struct Switcharoo<'a, #[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend> {
+    shared: TxnRcContainer<'a, B>,
+    local: Option<DbAnyTxn<'a, B>>,
+    hooks: TxnHooksRc,
+    txlog: TxnLogger,
+    // set on write transactions when auditing is on
+    audit: Option<AuditContext>,
+    savepoints: u32
+}
+
+/// Nested transaction inside the request transaction. Consume with release or rollback_to,
+/// anything left open is released by the final commit (or discarded by the rollback).
+#[must_use]
+pub struct Savepoint {
+    name: String
+}
+
+pub struct TestTransactionHolder<'a, #[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend> {
+    pub for_handler: Switcharoo<'a, B>,
+    pub for_context: TxnRcContainer<'a, B>,
+    pub for_hooks: TxnHooksRc,
+}
+
+type TxnHook = LocalBoxFuture<'static, anyhow::Result<()>>;
+
+/// Side effects (emails, enqueueing work, ...) that must only happen once the transaction is closed.
+/// Registered by the handler through WriteTxn, run by TxnMiddleware after commit/rollback. Hook errors
+/// are logged and counted but do not change the response.
+#[derive(Default)]
+pub struct TxnHooks {
+    after_commit: Vec<TxnHook>,
+    after_rollback: Vec<TxnHook>,
+}
 
-pub struct Switcharoo<'a> {
-    shared: TxnRcContainer<'a>,
-    local: Option<DbTxn<'a>>
+pub type TxnHooksRc = Rc<RefCell<TxnHooks>>;
+
+impl TxnHooks {
+    pub async fn run_after_commit(hooks: &TxnHooksRc) {
+        let pending = std::mem::take(&mut hooks.borrow_mut().after_commit);
+        hooks.borrow_mut().after_rollback.clear();
+        run_hooks(pending, "commit").await
+    }
+
+    pub async fn run_after_rollback(hooks: &TxnHooksRc) {
+        let pending = std::mem::take(&mut hooks.borrow_mut().after_rollback);
+        hooks.borrow_mut().after_commit.clear();
+        run_hooks(pending, "rollback").await
+    }
 }
 
-pub struct TestTransactionHolder<'a> {
-    pub for_handler: Switcharoo<'a>,
-    pub for_context: TxnRcContainer<'a>,
+async fn run_hooks(pending: Vec<TxnHook>, kind: &'static str) {
+    for hook in pending {
+        if let Err(e) = hook.await {
+            log::error!("Transaction hook after {kind} failed: {e:?}");
+            counter!("txn_hook_errors", 1, "after" => kind);
+        }
+    }
 }
 
-impl<'a> Switcharoo<'a> {
-    pub fn from_request(req: &HttpRequest) -> Result<(web::Data<DbMain>, Switcharoo<'static>), Error> {
+impl<'a, B: Backend> Switcharoo<'a, B> {
+    pub fn from_request(req: &HttpRequest) -> Result<(web::Data<DbMain<B>>, Switcharoo<'static, B>), Error> {
         // get stuff
         let exts = req.extensions();
-        let txcont: TxnRcContainer = exts.get::<TxnRcContainer>()
+        let txcont: TxnRcContainer<B> = exts.get::<TxnRcContainer<B>>()
             .ok_or(Error::from(AnyHandlerError::from(ApiError::InvalidState(String::from("TxnContainer missing in req")))))?
             .clone();
-        let db = req.app_data::<web::Data<DbMain>>()
+        let hooks: TxnHooksRc = exts.get::<TxnHooksRc>()
+            .ok_or(Error::from(AnyHandlerError::from(ApiError::InvalidState(String::from("TxnHooks missing in req")))))?
+            .clone();
+        // the audit context may add the request id to the extensions
+        drop(exts);
+        let db = req.app_data::<web::Data<DbMain<B>>>()
             .ok_or(Error::from(AnyHandlerError::from(ApiError::InvalidState(String::from("Data<DbMain> missing in request")))))?
             .clone();
 
         Ok((db, Switcharoo {
             shared: txcont,
-            local: None
+            local: None,
+            hooks,
+            txlog: TxnLogger::from_request(req),
+            audit: AuditContext::from_request(req).map_err(AnyHandlerError::from)?,
+            savepoints: 0
         }))
     }
 
     // for tests
     #[allow(unused)]
-    pub fn from_tx(txn: anyhow::Result<DbTxn<'a>>) -> anyhow::Result<TestTransactionHolder<'a>> {
-        let tx_ok = txn?;
+    pub fn from_tx(txn: anyhow::Result<DbWriteTxn<'a, B>>) -> anyhow::Result<TestTransactionHolder<'a, B>> {
+        Self::from_tx_with(txn, TxnLogger::default())
+    }
+
+    // like from_tx, savepoints are recorded in `log`
+    #[cfg(feature = "test-support")]
+    pub fn from_tx_logged(txn: anyhow::Result<DbWriteTxn<'a, B>>, log: &TxnLog) -> anyhow::Result<TestTransactionHolder<'a, B>> {
+        Self::from_tx_with(txn, TxnLogger(Some(log.clone())))
+    }
+
+    fn from_tx_with(txn: anyhow::Result<DbWriteTxn<'a, B>>, txlog: TxnLogger) -> anyhow::Result<TestTransactionHolder<'a, B>> {
+        let tx_ok = txn?.into();
         let shared = Rc::new(Cell::new(Some(tx_ok)));
         let shared2 = Rc::clone(&shared);
+        let hooks: TxnHooksRc = Rc::new(RefCell::new(TxnHooks::default()));
         Ok(TestTransactionHolder {
             for_handler: Switcharoo {
                 shared,
-                local: None
+                local: None,
+                hooks: Rc::clone(&hooks),
+                txlog,
+                audit: None,
+                savepoints: 0
             },
-            for_context: shared2
+            for_context: shared2,
+            for_hooks: hooks
         })
     }
 
-    pub fn get<'x, 'y>(&'x mut self) -> &mut DbWriteTxn<'a> {
+    fn local(&mut self) -> &mut DbAnyTxn<'a, B> {
         if self.local.is_none() {
             let txn = self.shared.replace(None).unwrap();
             self.local = Some(txn);
@@ -189,17 +539,84 @@ impl<'a> Switcharoo<'a> {
         }
     }
 
-    pub fn put(self, tx: anyhow::Result<DbTxn<'a>>) -> Result<Switcharoo, Error> {
-        let txn = tx.map_err(|e| Error::from(AnyHandlerError::from(e)))?;
-        log_txn_state(TxnState::Started);
-        self.shared.set(Some(txn));
+    // only ReadTxn and ReadThenWriteTxn (before its write()) hold a read transaction here, and they don't call this.
+    // Switcharoos made with from_tx or put always have a write transaction.
+    pub fn get(&mut self) -> &mut DbWriteTxn<'a, B> {
+        self.local().write().expect("read transaction used for writing")
+    }
+
+    // like local() but there may be no transaction yet (ReadThenWriteTxn)
+    fn started(&mut self) -> Option<&mut DbAnyTxn<'a, B>> {
+        if self.local.is_none() {
+            self.local = self.shared.replace(None);
+        }
+        self.local.as_mut()
+    }
+
+    fn start(&mut self, txn: DbAnyTxn<'a, B>) {
+        self.txlog.log(TxnState::Started);
+        self.local = Some(self.audited(txn));
+    }
+
+    fn audited(&self, mut txn: DbAnyTxn<'a, B>) -> DbAnyTxn<'a, B> {
+        if let (Some(ctx), Some(w)) = (&self.audit, txn.write()) {
+            w.set_audit(ctx.clone());
+        }
+        txn
+    }
+
+    pub fn get_read(&mut self) -> &mut DbReadTxn<'a, B> {
+        self.local().read()
+    }
+
+    pub fn after_commit<F: Future<Output = anyhow::Result<()>> + 'static>(&self, hook: F) {
+        self.hooks.borrow_mut().after_commit.push(Box::pin(hook));
+    }
+
+    pub fn after_rollback<F: Future<Output = anyhow::Result<()>> + 'static>(&self, hook: F) {
+        self.hooks.borrow_mut().after_rollback.push(Box::pin(hook));
+    }
+
+    pub async fn savepoint(&mut self) -> anyhow::Result<Savepoint> {
+        self.savepoints += 1;
+        let sp = Savepoint { name: format!("bear_sp_{}", self.savepoints) };
+        sqlx::query_with(&format!("SAVEPOINT {}", sp.name), Args::<B>::new()).execute(self.get().conn()).await?;
+        self.txlog.log(TxnState::SavepointStarted);
+        Ok(sp)
+    }
+
+    // keeps the changes made since the savepoint (they still depend on the outer commit)
+    pub async fn release(&mut self, sp: Savepoint) -> anyhow::Result<()> {
+        sqlx::query_with(&format!("RELEASE SAVEPOINT {}", sp.name), Args::<B>::new()).execute(self.get().conn()).await?;
+        self.txlog.log(TxnState::SavepointReleased);
+        Ok(())
+    }
+
+    // undoes the changes made since the savepoint, the outer transaction stays usable
+    pub async fn rollback_to(&mut self, sp: Savepoint) -> anyhow::Result<()> {
+        // ROLLBACK TO leaves the savepoint on the stack, so release it as well
+        sqlx::query_with(&format!("ROLLBACK TO SAVEPOINT {}", sp.name), Args::<B>::new()).execute(self.get().conn()).await?;
+        sqlx::query_with(&format!("RELEASE SAVEPOINT {}", sp.name), Args::<B>::new()).execute(self.get().conn()).await?;
+        self.txlog.log(TxnState::SavepointRolledBack);
+        Ok(())
+    }
+
+    pub fn put(self, tx: anyhow::Result<DbWriteTxn<'a, B>>) -> Result<Switcharoo<'a, B>, Error> {
+        self.put_any(tx)
+    }
+
+    // read transactions only go in through here, for ReadTxn
+    fn put_any<T: Into<DbAnyTxn<'a, B>>>(self, tx: anyhow::Result<T>) -> Result<Switcharoo<'a, B>, Error> {
+        let txn = tx.map_err(|e| Error::from(AnyHandlerError::from(e)))?.into();
+        self.txlog.log(TxnState::Started);
+        self.shared.set(Some(self.audited(txn)));
         //log::debug!("Started tx");
 
         Ok(self)
     }
 }
 
-impl Drop for Switcharoo<'_> {
+impl<B: Backend> Drop for Switcharoo<'_, B> {
     fn drop(&mut self) {
         if self.local.is_some() {
             self.shared.set(self.local.take());
@@ -208,28 +625,30 @@ impl Drop for Switcharoo<'_> {
     }
 }
 
-impl<'a> TestTransactionHolder<'a> {
+impl<'a, B: Backend> TestTransactionHolder<'a, B> {
 
-    pub fn into_tuple(self) -> (Switcharoo<'a>, TxnRcContainer<'a>) {
+    pub fn into_tuple(self) -> (Switcharoo<'a, B>, TxnRcContainer<'a, B>) {
         (self.for_handler, self.for_context)
     }
 }
 
-pub struct WriteTxn<'a>(pub Switcharoo<'a>);
-pub struct ReadTxn<'a>(pub Switcharoo<'a>);
+pub struct WriteTxn<'a, #[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend>(pub Switcharoo<'a, B>);
+// private: the Switcharoo inside holds a read transaction, its get() would panic
+pub struct ReadTxn<'a, #[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend>(Switcharoo<'a, B>);
 
-impl FromRequest for WriteTxn<'static> {
+impl<B: Backend> FromRequest for WriteTxn<'static, B> {
     type Error = Error;
-    type Future = LocalBoxFuture<'static, Result<WriteTxn<'static>, Error>>;
+    type Future = LocalBoxFuture<'static, Result<WriteTxn<'static, B>, Error>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
         match Switcharoo::from_request(req) {
             Err(e) => {
-                return Box::pin(ready(Err(e)))
+                Box::pin(ready(Err(e)))
             },
             Ok((db, sw)) => {
+                let route = route_pattern(req);
                 Box::pin(async move {
-                    let txn = db.newtx_write().await;
+                    let txn = db.newtx_write_for(&route).await;
                     Ok(WriteTxn(sw.put(txn)?))
                 })
             }
@@ -237,31 +656,109 @@ impl FromRequest for WriteTxn<'static> {
     }
 }
 
-impl<'a> WriteTxn<'a> {
-    pub fn get<'x, 'y>(&'x mut self) -> &mut DbWriteTxn<'a> {
+impl<'a, B: Backend> WriteTxn<'a, B> {
+    pub fn get(&mut self) -> &mut DbWriteTxn<'a, B> {
         self.0.get()
     }
+
+    pub fn after_commit<F: Future<Output = anyhow::Result<()>> + 'static>(&self, hook: F) {
+        self.0.after_commit(hook)
+    }
+
+    pub fn after_rollback<F: Future<Output = anyhow::Result<()>> + 'static>(&self, hook: F) {
+        self.0.after_rollback(hook)
+    }
+
+    pub async fn savepoint(&mut self) -> anyhow::Result<Savepoint> {
+        self.0.savepoint().await
+    }
+
+    pub async fn release(&mut self, sp: Savepoint) -> anyhow::Result<()> {
+        self.0.release(sp).await
+    }
+
+    pub async fn rollback_to(&mut self, sp: Savepoint) -> anyhow::Result<()> {
+        self.0.rollback_to(sp).await
+    }
 }
 
-impl<'a> ReadTxn<'a> {
-    pub fn get<'x, 'y>(&'x mut self) -> &mut DbTxn<'a> {
-        self.0.get()
+/// Two transactions one after the other, so that slow pre-work (HTTP calls, validation) does not hold
+/// the single writer connection: read() serves from a reader transaction, write() ends it and begins a
+/// separate writer transaction. Nothing carries over from the reads, the writer sees whatever was
+/// committed in between, so anything the write depends on must be re-read (or checked) after write().
+/// Neither is begun before it's needed. Hooks run by the response when no transaction was begun, as if
+/// there had been one.
+pub struct ReadThenWriteTxn<'a, #[cfg(feature = "sqlite")] B: Backend = Sqlite, #[cfg(not(feature = "sqlite"))] B: Backend> {
+    sw: Switcharoo<'a, B>,
+    db: web::Data<DbMain<B>>,
+    route: String,
+}
+
+impl<'a, B: Backend> ReadThenWriteTxn<'a, B> {
+    pub async fn read(&mut self) -> anyhow::Result<&mut DbReadTxn<'a, B>> {
+        if self.sw.started().is_none() {
+            let txn = self.db.newtx_read_for(&self.route).await?;
+            self.sw.start(txn.into());
+        }
+        Ok(self.sw.get_read())
+    }
+
+    pub async fn write(&mut self) -> anyhow::Result<&mut DbWriteTxn<'a, B>> {
+        let writing = matches!(self.sw.started(), Some(DbAnyTxn::Write(_)));
+        if !writing {
+            if let Some(reading) = self.sw.local.take() {
+                // nothing to keep from the read snapshot
+                self.sw.txlog.log(TxnState::Committed);
+                reading.commit().await?;
+            }
+            let txn = self.db.newtx_write_for(&self.route).await?;
+            self.sw.start(txn.into());
+        }
+        Ok(self.sw.get())
+    }
+
+    pub fn is_writing(&mut self) -> bool {
+        matches!(self.sw.started(), Some(DbAnyTxn::Write(_)))
+    }
+
+    pub fn after_commit<F: Future<Output = anyhow::Result<()>> + 'static>(&self, hook: F) {
+        self.sw.after_commit(hook)
+    }
+
+    pub fn after_rollback<F: Future<Output = anyhow::Result<()>> + 'static>(&self, hook: F) {
+        self.sw.after_rollback(hook)
+    }
+}
+
+impl<B: Backend> FromRequest for ReadThenWriteTxn<'static, B> {
+    type Error = Error;
+    type Future = Ready<Result<ReadThenWriteTxn<'static, B>, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Switcharoo::from_request(req).map(|(db, sw)| ReadThenWriteTxn { sw, db, route: route_pattern(req) }))
+    }
+}
+
+impl<'a, B: Backend> ReadTxn<'a, B> {
+    pub fn get(&mut self) -> &mut DbReadTxn<'a, B> {
+        self.0.get_read()
     }
 }
 
-impl FromRequest for ReadTxn<'static> {
+impl<B: Backend> FromRequest for ReadTxn<'static, B> {
     type Error = Error;
-    type Future = LocalBoxFuture<'static, Result<ReadTxn<'static>, Error>>;
+    type Future = LocalBoxFuture<'static, Result<ReadTxn<'static, B>, Error>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
         match Switcharoo::from_request(req) {
             Err(e) => {
-                return Box::pin(ready(Err(e)))
+                Box::pin(ready(Err(e)))
             },
             Ok((db, sw)) => {
+                let route = route_pattern(req);
                 Box::pin(async move {
-                    let txn = db.newtx_read().await;
-                    Ok(ReadTxn(sw.put(txn)?))
+                    let txn = db.newtx_read_for(&route).await;
+                    Ok(ReadTxn(sw.put_any(txn)?))
                 })
             }
         }
@@ -16,6 +16,23 @@ pub fn gentoken<T: From<String>>() -> T {
     T::from(bs58::encode(buf).into_string())
 }
 
+const CROCKFORD32: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/** Generates a ulid-like token: 48 bit unix millis + 80 random bits as 26 chars of crockford base32.
+    Fixed width, so tokens sort by creation time, which keeps index inserts local. */
+pub fn gen_sortable_token() -> String {
+    let millis = chrono::Utc::now().timestamp_millis() as u128 & 0xffff_ffff_ffff;
+    let mut buf = [0u8; 10];
+    rand::thread_rng().fill_bytes(&mut buf);
+    let mut n = buf.iter().fold(millis, |acc, b| (acc << 8) | *b as u128);
+    let mut out = [0u8; 26];
+    for c in out.iter_mut().rev() {
+        *c = CROCKFORD32[(n & 31) as usize];
+        n >>= 5;
+    }
+    String::from_utf8(out.to_vec()).unwrap()
+}
+
 // for now capital letters and numbers
 pub fn gen_alphabetical(len: usize) -> String {
     let mut result = rand::thread_rng().sample_iter(rand::distributions::Alphanumeric).take(len).map(char::from).collect::<String>();
@@ -80,8 +97,8 @@ pub type Instant = i64;
 
 pub trait Clock : Send + Sync {
     // returns seconds
-    fn utcnow(self: &Self) -> Instant;
-    fn advance(self: &mut Self, by: i64) -> Instant;
+    fn utcnow(&self) -> Instant;
+    fn advance(&mut self, by: i64) -> Instant;
 }
 
 pub struct RealClock();
@@ -92,10 +109,16 @@ impl RealClock {
     }
 }
 
+impl Default for RealClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Clock for RealClock {
     // seconds timestamp
-    fn utcnow(self: &Self) -> Instant { chrono::offset::Utc::now().timestamp() }
-    fn advance(self: &mut Self, _by: i64) -> Instant {
+    fn utcnow(&self) -> Instant { chrono::offset::Utc::now().timestamp() }
+    fn advance(&mut self, _by: i64) -> Instant {
         panic!("Using real clock, can't manually advance.")
     }
 }
@@ -112,6 +135,12 @@ impl MockClock {
 
 }
 
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Clone for MockClock {
     fn clone(&self) -> Self {
         MockClock { time: self.time }
@@ -119,9 +148,9 @@ impl Clone for MockClock {
 }
 
 impl Clock for MockClock {
-    fn utcnow(self: &Self) -> Instant { self.time }
+    fn utcnow(&self) -> Instant { self.time }
 
-    fn advance(self: &mut Self, by: i64) -> Instant {
+    fn advance(&mut self, by: i64) -> Instant {
         self.time += by;
         self.time
     }
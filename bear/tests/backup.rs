@@ -0,0 +1,93 @@
+// Backups and restore, sqlite only: they work on the database file.
+#![cfg(feature = "sqlite")]
+
+use std::path::{Path, PathBuf};
+use bear::backup::*;
+use bear::db::{db_init, DbMain};
+use sqlx::{Connection, Executor, SqliteConnection};
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bear-backup-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+async fn source(dir: &Path) -> DbMain {
+    let db: DbMain = db_init(&format!("sqlite:{}", dir.join("app.db").display()), &sqlx::migrate::Migrator::DEFAULT).await.unwrap();
+    let mut txn = db.newtx_write().await.unwrap();
+    txn.conn().execute("CREATE TABLE docs (id TEXT PRIMARY KEY NOT NULL, body TEXT NOT NULL); \
+        INSERT INTO docs VALUES ('a', 'first'), ('b', 'second')").await.unwrap();
+    txn.commit().await.unwrap();
+    db
+}
+
+async fn docs(path: &Path) -> i64 {
+    let mut conn = SqliteConnection::connect(&format!("sqlite:{}", path.display())).await.unwrap();
+    let n = sqlx::query_scalar("SELECT COUNT(*) FROM docs").fetch_one(&mut conn).await.unwrap();
+    conn.close().await.unwrap();
+    n
+}
+
+fn file_names(paths: &[PathBuf]) -> Vec<String> {
+    paths.iter().map(|it| it.file_name().unwrap().to_string_lossy().into_owned()).collect()
+}
+
+#[tokio::test]
+async fn backup_rotate_restore() {
+    let dir = scratch("round_trip");
+    let db = source(&dir).await;
+    let settings = BackupSettings { dir: dir.join("backups"), keep: 2, compress: true, prefix: String::from("app") };
+
+    for now in [1000, 2000, 3000] {
+        let info = backup(&db, &settings, now).await.unwrap();
+        assert!(info.file.ends_with(".db.gz") && info.size > 0, "{info:?}");
+    }
+    let kept = list_backups(&settings).await.unwrap();
+    assert_eq!(file_names(&kept), ["app-19700101-005000.db.gz", "app-19700101-003320.db.gz"]);
+
+    let plain = BackupSettings { compress: false, ..settings.clone() };
+    let info = backup(&db, &plain, 4000).await.unwrap();
+    assert!(info.file.ends_with("app-19700101-010640.db"), "{info:?}");
+    assert_eq!(list_backups(&settings).await.unwrap().len(), 2);
+    // the same second again
+    assert!(backup(&db, &plain, 4000).await.is_err());
+
+    let from_gz = dir.join("restored-gz.db");
+    restore_backup(&list_backups(&settings).await.unwrap()[1], &from_gz).await.unwrap();
+    assert_eq!(docs(&from_gz).await, 2);
+
+    let from_plain = dir.join("restored.db");
+    restore_backup(Path::new(&info.file), &from_plain).await.unwrap();
+    verify_backup(&from_plain).await.unwrap();
+    assert_eq!(docs(&from_plain).await, 2);
+    assert!(!dir.join("restored.db.restoring").exists());
+}
+
+#[tokio::test]
+async fn corrupt_backups_are_rejected() {
+    let dir = scratch("corrupt");
+    let db = source(&dir).await;
+    let settings = BackupSettings { dir: dir.join("backups"), keep: 2, compress: false, prefix: String::from("app") };
+    let info = backup(&db, &settings, 1000).await.unwrap();
+
+    // overwrite the table pages, the header stays valid
+    let mut bytes = std::fs::read(&info.file).unwrap();
+    let len = bytes.len();
+    bytes[len / 2..].fill(0xa5);
+    let corrupt = dir.join("corrupt.db");
+    std::fs::write(&corrupt, bytes).unwrap();
+    assert!(verify_backup(&corrupt).await.is_err());
+
+    let target = dir.join("target.db");
+    assert!(restore_backup(&corrupt, &target).await.is_err());
+    assert!(!target.exists());
+    assert!(!dir.join("target.db.restoring").exists());
+
+    // not gzip at all, fails while unpacking
+    let bad_gz = dir.join("bad.db.gz");
+    std::fs::write(&bad_gz, b"not gzip").unwrap();
+    assert!(restore_backup(&bad_gz, &target).await.is_err());
+    assert!(!target.exists());
+    assert!(!dir.join("target.db.restoring").exists());
+}
@@ -0,0 +1,189 @@
+// Runs the generic db helpers against the backend bear was built for, see common for the database.
+
+mod common;
+
+use actix_web::{test, web, App, HttpResponse, ResponseError};
+use actix_web::dev::Service;
+use actix_web::http::StatusCode;
+use bear::errors::{AnyHandlerError, ApiError};
+use bear::audit::{AuditContext, ROW_AUDIT_SCHEMA};
+use bear::db::*;
+use bear::query::{col, Dir, Select};
+use bear::backend::{Backend, BackendSql};
+use bear::txnmw::{ReadTxn, TxnMidFactory};
+use common::{doc, test_db, Doc, TestBackend, DOCS_TABLE};
+use sqlx::Executor;
+
+#[tokio::test]
+async fn generic_helpers() -> anyhow::Result<()> {
+    let db = test_db("db_backend", &[DOCS_TABLE, ROW_AUDIT_SCHEMA.on::<TestBackend>()]).await;
+
+    let mut txn = db.newtx_write().await?;
+    txn.set_audit(AuditContext { principal: Some(String::from("test:p")), ..AuditContext::new(1234) });
+    for (i, id) in ["a", "b", "c", "d"].iter().enumerate() {
+        insert(&mut txn, &doc(id, i as i64)).await?;
+    }
+    upsert(&mut txn, &Doc { body: String::from("upserted"), ..doc("a", 0) }).await?;
+    assert_eq!(update_fields_versioned::<Doc, _>(&mut txn, "a", 0, Assignments::new().set("body", "v1")).await?, 1);
+    let stale = update_fields_versioned::<Doc, _>(&mut txn, "a", 0, Assignments::new().set("body", "v2")).await.unwrap_err();
+    assert!(matches!(stale.downcast_ref::<ApiError>(), Some(ApiError::Conflict(_))));
+    assert_eq!(update_fields::<Doc, _>(&mut txn, "b", Assignments::new().set("body", "bb").set("version", 7i64)).await?, 1);
+    assert!(soft_delete::<Doc, _>(&mut txn, "c", 100).await?);
+    assert!(delete_by_id::<Doc, _>(&mut txn, "d").await?);
+    txn.commit().await?;
+
+    let mut txn = db.newtx_read().await?;
+    assert_eq!(find_by_id::<Doc, _>(&mut txn, "a").await?.map(|it| it.body), Some(String::from("v1")));
+    assert_eq!(find_by_id::<Doc, _>(&mut txn, "c").await?, None);
+    assert!(find_by_id_with_deleted::<Doc, _>(&mut txn, "c").await?.is_some());
+    assert_eq!(count::<Doc, _>(&mut txn).await?, 2);
+
+    let rows = Select::<Doc, _>::new()
+        .filter(col("id").is_in(["a", "b", "c", "d"]))
+        .filter(col("version").between(1i64, 10i64))
+        .order_by("id", Dir::Desc)
+        .offset(0)
+        .fetch_all(&mut txn).await?;
+    assert_eq!(rows.iter().map(|it| it.id.as_str()).collect::<Vec<&str>>(), ["b", "a"]);
+
+    let audited: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM row_audit WHERE principal = $1 AND created = 1234")
+        .bind("test:p")
+        .fetch_one(&mut *txn).await?;
+    // 4 inserts, upsert, 2 updates, soft delete, delete
+    assert_eq!(audited, 9);
+    let versions: String = sqlx::query_scalar("SELECT new_values FROM row_audit WHERE row_id = $1 AND action = $2")
+        .bind("b")
+        .bind("update")
+        .fetch_one(&mut *txn).await?;
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&versions)?["version"], 7);
+
+    // readers can't write
+    assert!(txn.conn().execute("DELETE FROM docs").await.is_err());
+    txn.rollback().await?;
+    Ok(())
+}
+
+fn db_error_code(e: sqlx::Error) -> String {
+    e.as_database_error().and_then(|it| it.code()).map(|it| it.into_owned()).unwrap_or_default()
+}
+
+async fn write_in_read(mut txn: ReadTxn<'_, TestBackend>) -> HttpResponse {
+    match txn.get().conn().execute("INSERT INTO docs (id, body, version) VALUES ('r', 'r', 0)").await {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::Forbidden().body(db_error_code(e)),
+    }
+}
+
+#[actix_web::test]
+async fn readers_refuse_writes() -> anyhow::Result<()> {
+    let db = test_db("db_read_only", &[DOCS_TABLE]).await;
+    // SQLITE_READONLY for query_only, read_only_sql_transaction for default_transaction_read_only
+    let read_only = TestBackend::by_backend("8", "25006");
+
+    let mut txn = db.newtx_read().await?;
+    let e = txn.conn().execute("INSERT INTO docs (id, body, version) VALUES ('r', 'r', 0)").await.unwrap_err();
+    assert_eq!(db_error_code(e), read_only);
+    txn.rollback().await?;
+
+    let app = test::init_service(App::new()
+        .app_data(web::Data::new(db.clone()))
+        .wrap(TxnMidFactory::new(db.clone()))
+        .route("/write", web::post().to(write_in_read))).await;
+    let res = app.call(test::TestRequest::post().uri("/write").to_request()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(test::read_body(res).await, read_only);
+
+    let mut txn = db.newtx_read().await?;
+    assert_eq!(count::<Doc, _>(&mut txn).await?, 0);
+    txn.rollback().await?;
+    Ok(())
+}
+
+// a hand written impl that declares fewer columns than the table has
+#[derive(sqlx::FromRow)]
+struct Legacy {
+    #[allow(dead_code)]
+    id: String,
+}
+
+impl TableMetadata for Legacy {
+    fn table_name() -> &'static str { "docs" }
+    fn columns() -> &'static [&'static str] { &["id"] }
+}
+
+fn unknown_column(e: anyhow::Error) -> String {
+    match e.downcast_ref::<ApiError>() {
+        Some(ApiError::UnknownColumn(it)) => it.clone(),
+        _ => panic!("expected UnknownColumn, got {e:?}"),
+    }
+}
+
+#[tokio::test]
+async fn undeclared_columns_are_rejected() -> anyhow::Result<()> {
+    let db = test_db("db_columns", &[DOCS_TABLE]).await;
+    let mut txn = db.newtx_write().await?;
+    insert(&mut txn, &doc("a", 0)).await?;
+
+    let e = find_opt_field::<Doc, _, _>(&mut txn, "password_hash", &"x").await.err().unwrap();
+    assert_eq!(unknown_column(e), "docs.password_hash");
+    let e = update_field::<Doc, _, _>(&mut txn, "is_admin = 1, body", &"x", "a").await.unwrap_err();
+    assert_eq!(unknown_column(e), "docs.is_admin = 1, body");
+    let e = Select::<Doc, _>::new().filter(col("secret").eq("x")).fetch_all(&mut txn).await.unwrap_err();
+    assert_eq!(unknown_column(e), "docs.secret");
+    let e = Select::<Doc, _>::new().order_by("secret", Dir::Asc).fetch_all(&mut txn).await.unwrap_err();
+    // names like this come from the request, a client error
+    assert_eq!(AnyHandlerError::from(e).error_response().status(), StatusCode::BAD_REQUEST);
+
+    // undeclared columns don't get through, even real ones
+    let e = find_opt_field::<Legacy, _, _>(&mut txn, "body", &"x").await.err().unwrap();
+    assert_eq!(unknown_column(e), "docs.body");
+    let e = update_field::<Legacy, _, _>(&mut txn, "body", &"x", "a").await.unwrap_err();
+    assert_eq!(unknown_column(e), "docs.body");
+
+    assert_eq!(find_opt_field::<Doc, _, _>(&mut txn, "body", &"body a").await?.map(|it| it.id), Some(String::from("a")));
+    txn.rollback().await?;
+    Ok(())
+}
+
+const NOTES_TABLE: BackendSql = BackendSql {
+    sqlite: "CREATE TABLE notes (id INTEGER PRIMARY KEY AUTOINCREMENT, body TEXT NOT NULL)",
+    postgres: "CREATE TABLE notes (id BIGSERIAL PRIMARY KEY, body TEXT NOT NULL)",
+};
+
+#[derive(bear::BearTable, Debug, PartialEq)]
+#[table = "notes"]
+struct Note {
+    id: Option<i64>,
+    body: String,
+}
+
+#[tokio::test]
+async fn unset_ids_are_assigned_by_the_database() -> anyhow::Result<()> {
+    let db = test_db("db_serial", &[NOTES_TABLE.on::<TestBackend>(), ROW_AUDIT_SCHEMA.on::<TestBackend>()]).await;
+    let mut txn = db.newtx_write().await?;
+    insert(&mut txn, &Note { id: None, body: String::from("first") }).await?;
+    txn.set_audit(AuditContext::new(1234));
+    insert(&mut txn, &Note { id: None, body: String::from("second") }).await?;
+    upsert(&mut txn, &Note { id: None, body: String::from("third") }).await?;
+    insert(&mut txn, &Note { id: Some(10), body: String::from("tenth") }).await?;
+    upsert(&mut txn, &Note { id: Some(10), body: String::from("ten") }).await?;
+    txn.commit().await?;
+
+    let mut txn = db.newtx_read().await?;
+    let notes: Vec<(i64, String)> = sqlx::query_as("SELECT id, body FROM notes ORDER BY id")
+        .fetch_all(&mut *txn).await?;
+    assert_eq!(notes, [(1, String::from("first")), (2, String::from("second")), (3, String::from("third")), (10, String::from("ten"))]);
+    assert_eq!(find_opt_field::<Note, _, _>(&mut txn, "body", &"second").await?, Some(Note { id: Some(2), body: String::from("second") }));
+    let audited: Vec<String> = sqlx::query_scalar("SELECT row_id FROM row_audit WHERE action = $1 ORDER BY id")
+        .bind("insert")
+        .fetch_all(&mut *txn).await?;
+    assert_eq!(audited, ["2", "10"]);
+    // found by its integer id for the old values
+    let old: String = sqlx::query_scalar("SELECT old_values FROM row_audit WHERE action = $1 AND row_id = $2")
+        .bind("upsert")
+        .bind("10")
+        .fetch_one(&mut *txn).await?;
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&old)?["body"], "tenth");
+    txn.rollback().await?;
+    Ok(())
+}
@@ -0,0 +1,90 @@
+// DbSettings: validation, and that the sqlite pragmas reach the connections.
+
+use bear::cfg::DbSettings;
+use bear::errors::ApiError;
+
+fn invalid(settings: DbSettings) -> String {
+    match settings.validate().unwrap_err().downcast::<ApiError>() {
+        Ok(ApiError::InvalidState(it)) => it,
+        other => panic!("expected InvalidState, got {other:?}"),
+    }
+}
+
+#[test]
+fn validation() {
+    DbSettings::default().validate().unwrap();
+    DbSettings { synchronous: String::from("NORMAL"), cache_size: Some(-2000), ..Default::default() }.validate().unwrap();
+
+    assert_eq!(invalid(DbSettings { synchronous: String::from("sometimes"), ..Default::default() }), "db.synchronous.invalid");
+    assert_eq!(invalid(DbSettings { busy_timeout_ms: 3_600_000, ..Default::default() }), "db.busy_timeout_ms.invalid");
+    assert_eq!(invalid(DbSettings { reader_pool_size: 0, ..Default::default() }), "db.reader_pool_size.invalid");
+    assert_eq!(invalid(DbSettings { writer_pool_size: 0, ..Default::default() }), "db.writer_pool_size.invalid");
+    assert_eq!(invalid(DbSettings { cache_size: Some(0), ..Default::default() }), "db.cache_size.invalid");
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use bear::cfg::DbSettings;
+    use bear::errors::ApiError;
+    use bear::db::{db_init_with, DbMain};
+    use sqlx::{Executor, Row};
+
+    fn url(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("bear-test-{}-{name}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        format!("sqlite:{}", path.display())
+    }
+
+    async fn init(name: &str, settings: &DbSettings) -> anyhow::Result<DbMain> {
+        db_init_with(&url(name), &sqlx::migrate::Migrator::DEFAULT, settings).await
+    }
+
+    async fn refused(name: &str, settings: &DbSettings) -> Option<ApiError> {
+        match init(name, settings).await {
+            Ok(_) => None,
+            Err(e) => e.downcast::<ApiError>().ok(),
+        }
+    }
+
+    async fn pragma<'c, E: Executor<'c, Database = sqlx::Sqlite>>(conn: E, name: &str) -> i64 {
+        sqlx::query(&format!("PRAGMA {name}")).fetch_one(conn).await.unwrap().get(0)
+    }
+
+    #[tokio::test]
+    async fn pragmas_are_applied() {
+        let settings = DbSettings {
+            busy_timeout_ms: 1234,
+            synchronous: String::from("normal"),
+            cache_size: Some(-4000),
+            foreign_keys: false,
+            reader_pool_size: 2,
+            ..Default::default()
+        };
+        let db = init("settings_pragmas", &settings).await.unwrap();
+
+        let mut txn = db.newtx_write().await.unwrap();
+        assert_eq!(pragma(txn.conn(), "busy_timeout").await, 1234);
+        assert_eq!(pragma(txn.conn(), "cache_size").await, -4000);
+        assert_eq!(pragma(txn.conn(), "synchronous").await, 1);
+        assert_eq!(pragma(txn.conn(), "foreign_keys").await, 0);
+        assert_eq!(pragma(txn.conn(), "query_only").await, 0);
+        txn.conn().execute("CREATE TABLE docs (id TEXT PRIMARY KEY NOT NULL)").await.unwrap();
+        txn.commit().await.unwrap();
+
+        let mut txn = db.newtx_read().await.unwrap();
+        assert_eq!(pragma(&mut *txn, "busy_timeout").await, 1234);
+        assert_eq!(pragma(&mut *txn, "cache_size").await, -4000);
+        assert_eq!(pragma(&mut *txn, "query_only").await, 1);
+        assert!(txn.execute("INSERT INTO docs (id) VALUES ('a')").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_settings_are_refused() {
+        let settings = DbSettings { synchronous: String::from("sometimes"), ..Default::default() };
+        assert_eq!(refused("settings_invalid", &settings).await, Some(ApiError::InvalidState(String::from("db.synchronous.invalid"))));
+
+        // valid in general, sqlite has one writer
+        let settings = DbSettings { writer_pool_size: 2, ..Default::default() };
+        assert_eq!(refused("settings_writers", &settings).await, Some(ApiError::InvalidState(String::from("db.writer_pool_size.invalid"))));
+    }
+}
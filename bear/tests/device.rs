@@ -0,0 +1,100 @@
+// Device registration and the device token through the auth middleware.
+
+mod common;
+
+use actix_web::{test, web, App, HttpResponse};
+use actix_web::body::to_bytes;
+use actix_web::cookie::Cookie;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use bear::authmw::{AuthMidFactory, PrincipalDevice};
+use bear::device::{device_register, device_rotate, DEVICE_SCHEMA};
+use bear::oidc::SESSION_COOKIE_NAME;
+use bear::txnmw::TxnMidFactory;
+use common::{login, test_db, TestApp, TestBackend, TestSession, SESSIONS_TABLE};
+use serde_json::Value;
+
+type App1 = TestApp<TestSession>;
+
+async fn whoami(device: PrincipalDevice) -> HttpResponse {
+    HttpResponse::Ok().body(format!("{}:{}", device.device_id, device.parent.unwrap_or_default()))
+}
+
+macro_rules! app {
+    ($db:expr, $objs:expr) => {
+        test::init_service(App::new()
+            .app_data(web::Data::new($db.clone()))
+            .app_data($objs.clone())
+            .wrap(AuthMidFactory::<App1>::new($db.clone(), false))
+            .wrap(TxnMidFactory::new($db.clone()))
+            .route("/device", web::post().to(device_register::<App1>))
+            .route("/device/rotate", web::post().to(device_rotate::<App1>))
+            .route("/device/whoami", web::get().to(whoami))).await
+    };
+}
+
+// status and body, also when the auth middleware fails the request
+async fn outcome(res: Result<ServiceResponse, actix_web::Error>) -> (StatusCode, String) {
+    let res = match res {
+        Ok(it) => it.into_parts().1,
+        Err(e) => e.error_response(),
+    };
+    let status = res.status();
+    let body = to_bytes(res.into_body()).await.unwrap_or_default();
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+fn device(uri: &str, id: &str, token: &str) -> test::TestRequest {
+    test::TestRequest::default().uri(uri).insert_header(("Authorization", format!("Device {id} {token}")))
+}
+
+fn creds(body: &str) -> (String, String) {
+    let it: Value = serde_json::from_str(body).unwrap();
+    (it["device_id"].as_str().unwrap().into(), it["token"].as_str().unwrap().into())
+}
+
+#[actix_web::test]
+async fn register_authenticate_rotate() {
+    let db = test_db("device_rotate", &[SESSIONS_TABLE, DEVICE_SCHEMA.on::<TestBackend>()]).await;
+    let objs = TestApp::with_sessions(&[]);
+    let app = app!(db, objs);
+    let user = login(&db, &objs, "owner@bear.test").await;
+
+    let (status, body) = outcome(app.call(test::TestRequest::post().uri("/device")
+        .cookie(Cookie::new(SESSION_COOKIE_NAME, user)).to_request()).await).await;
+    assert_eq!(status, StatusCode::OK);
+    let (id, token) = creds(&body);
+
+    let (status, body) = outcome(app.call(device("/device/whoami", &id, &token).to_request()).await).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, format!("{id}:owner@bear.test"));
+
+    let (status, body) = outcome(app.call(device("/device/rotate", &id, &token).method(http::Method::POST).to_request()).await).await;
+    assert_eq!(status, StatusCode::OK);
+    let (rotated_id, rotated) = creds(&body);
+    assert_eq!(rotated_id, id);
+    assert_ne!(rotated, token);
+
+    let (status, body) = outcome(app.call(device("/device/whoami", &id, &token).to_request()).await).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body.contains("device.token.invalid"), "{body}");
+    let (status, _) = outcome(app.call(device("/device/whoami", &id, &rotated).to_request()).await).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn unknown_devices_and_anonymous_registration_fail() {
+    let db = test_db("device_unknown", &[SESSIONS_TABLE, DEVICE_SCHEMA.on::<TestBackend>()]).await;
+    let objs = TestApp::with_sessions(&[]);
+    let app = app!(db, objs);
+
+    let (status, body) = outcome(app.call(device("/device/whoami", "nope", "token").to_request()).await).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body.contains("device.unknown"), "{body}");
+
+    let (status, _) = outcome(app.call(test::TestRequest::post().uri("/device").to_request()).await).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let mut txn = db.newtx_read().await.unwrap();
+    let devices: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM device").fetch_one(&mut *txn).await.unwrap();
+    assert_eq!(devices, 0);
+}
@@ -0,0 +1,89 @@
+// If-Match parsing and the ETag round trip through handlers to a 409.
+
+mod common;
+
+use actix_web::{test, web, App, HttpResponse};
+use actix_web::dev::Service;
+use actix_web::http::StatusCode;
+use actix_web::http::header::{ETAG, IF_MATCH};
+use bear::errors::{AnyHandlerError, ApiError};
+use bear::etag::{etag, IfMatchVersion};
+use bear::db::*;
+use bear::txnmw::{ReadTxn, TxnMidFactory, WriteTxn};
+use common::{doc, test_db, Doc, TestBackend, DOCS_TABLE};
+
+fn parse(values: &[&str]) -> anyhow::Result<Option<i64>> {
+    let mut req = test::TestRequest::default();
+    for it in values {
+        req = req.append_header((IF_MATCH, *it));
+    }
+    IfMatchVersion::parse(&req.to_http_request()).map(|it| it.0)
+}
+
+fn invalid(res: anyhow::Result<Option<i64>>) -> bool {
+    matches!(res.map_err(|e| e.downcast::<ApiError>()), Err(Ok(ApiError::InvalidInput)))
+}
+
+#[actix_web::test]
+async fn if_match_parsing() {
+    assert_eq!(parse(&[]).unwrap(), None);
+    assert_eq!(parse(&["*"]).unwrap(), None);
+    assert_eq!(parse(&["\"7\""]).unwrap(), Some(7));
+    assert!(invalid(parse(&["W/\"7\""])));
+    assert!(invalid(parse(&["\"7\", \"8\""])));
+    assert!(invalid(parse(&["\"7\"", "\"8\""])));
+    assert!(invalid(parse(&["\"seven\""])));
+    assert!(invalid(parse(&["7"])));
+
+    let missing = IfMatchVersion(None).required().unwrap_err();
+    assert_eq!(missing.downcast_ref::<ApiError>(), Some(&ApiError::Any("if-match.missing".into())));
+}
+
+async fn get_doc(mut txn: ReadTxn<'_, TestBackend>, id: web::Path<String>) -> Result<HttpResponse, AnyHandlerError> {
+    let doc = find_by_id::<Doc, _>(txn.get(), &id).await?.ok_or(ApiError::NotFound("doc".into()))?;
+    Ok(HttpResponse::Ok().insert_header(etag(doc.version)).body(doc.body))
+}
+
+async fn edit_doc(mut txn: WriteTxn<'_, TestBackend>, id: web::Path<String>, ifm: IfMatchVersion, body: String) -> Result<HttpResponse, AnyHandlerError> {
+    let version = ifm.required()?;
+    update_fields_versioned::<Doc, _>(txn.get(), &id, version, Assignments::new().set("body", body)).await?;
+    Ok(HttpResponse::Ok().insert_header(etag(version + 1)).finish())
+}
+
+#[actix_web::test]
+async fn stale_etag_is_a_conflict() {
+    let db = test_db("etag_conflict", &[DOCS_TABLE]).await;
+    let mut txn = db.newtx_write().await.unwrap();
+    insert(&mut txn, &doc("a", 3)).await.unwrap();
+    txn.commit().await.unwrap();
+    let app = test::init_service(App::new()
+        .app_data(web::Data::new(db.clone()))
+        .wrap(TxnMidFactory::new(db.clone()))
+        .route("/docs/{id}", web::get().to(get_doc))
+        .route("/docs/{id}", web::put().to(edit_doc))).await;
+
+    let res = app.call(test::TestRequest::get().uri("/docs/a").to_request()).await.unwrap();
+    let tag = res.headers().get(ETAG).unwrap().clone();
+    assert_eq!(tag, "\"3\"");
+
+    let edit = |tag: Option<&str>| {
+        let req = test::TestRequest::put().uri("/docs/a").set_payload("edited");
+        match tag {
+            Some(it) => req.insert_header((IF_MATCH, it)),
+            None => req,
+        }.to_request()
+    };
+    let res = app.call(edit(Some(tag.to_str().unwrap()))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get(ETAG).unwrap(), "\"4\"");
+
+    // someone else's edit won
+    let res = app.call(edit(Some(tag.to_str().unwrap()))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let res = app.call(edit(None)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let res = app.call(test::TestRequest::get().uri("/docs/a").to_request()).await.unwrap();
+    assert_eq!(res.headers().get(ETAG).unwrap(), "\"4\"");
+    assert_eq!(test::read_body(res).await, "edited");
+}
@@ -0,0 +1,182 @@
+// Admin impersonation through the auth middleware, sessions are TestSession.
+
+mod common;
+
+use actix_web::{test, web, App, HttpResponse};
+use actix_web::cookie::Cookie;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use bear::errors::AnyHandlerError;
+use bear::authmw::{AuthMidFactory, PrincipalAdmin, PrincipalAny, PrincipalInner};
+use bear::db::DbMain;
+use bear::impersonate::{impersonate_start, impersonate_stop, Impersonation, IMPERSONATION_AUDIT_SCHEMA, IMPERSONATION_LIFETIME};
+use bear::interface::Session;
+use bear::oidc::SESSION_COOKIE_NAME;
+use bear::txnmw::TxnMidFactory;
+use common::{login, test_db, TestApp, TestBackend, TestSession, SESSIONS_TABLE};
+
+type App1 = TestApp<TestSession>;
+
+const ADMIN: &str = "admin@bear.test";
+const OTHER_ADMIN: &str = "other@bear.test";
+const USER: &str = "user@bear.test";
+
+async fn whoami(principal: PrincipalAny, imp: Impersonation) -> HttpResponse {
+    HttpResponse::Ok().body(format!("{}:{}", principal.0.principal, imp.admin.unwrap_or_default()))
+}
+
+async fn parent(principal: PrincipalAny) -> HttpResponse {
+    HttpResponse::Ok().body(principal.0.parent.clone().unwrap_or_default())
+}
+
+async fn admin_only(admin: PrincipalAdmin<App1>) -> Result<HttpResponse, AnyHandlerError> {
+    Ok(HttpResponse::Ok().body(admin.email))
+}
+
+async fn setup(name: &str) -> (DbMain<TestBackend>, web::Data<App1>) {
+    let db = test_db(name, &[SESSIONS_TABLE, IMPERSONATION_AUDIT_SCHEMA.on::<TestBackend>()]).await;
+    (db, TestApp::with_sessions(&[ADMIN, OTHER_ADMIN]))
+}
+
+async fn session(db: &DbMain<TestBackend>, code: &str) -> Option<TestSession> {
+    let mut txn = db.newtx_read().await.unwrap();
+    TestSession::find(&mut txn, code).await
+}
+
+async fn audit_count(db: &DbMain<TestBackend>, admin: &str) -> i64 {
+    let mut txn = db.newtx_read().await.unwrap();
+    sqlx::query_scalar("SELECT COUNT(*) FROM impersonation_audit WHERE admin = $1")
+        .bind(admin)
+        .fetch_one(&mut *txn).await.unwrap()
+}
+
+macro_rules! app {
+    ($db:expr, $objs:expr) => {
+        test::init_service(App::new()
+            .app_data(web::Data::new($db.clone()))
+            .app_data($objs.clone())
+            .wrap(AuthMidFactory::<App1>::new($db.clone(), false))
+            .wrap(TxnMidFactory::new($db.clone()))
+            .route("/whoami", web::get().to(whoami))
+            .route("/parent", web::get().to(parent))
+            .route("/admin", web::get().to(admin_only))
+            .route("/impersonate", web::post().to(impersonate_start::<App1>))
+            .route("/impersonate/stop", web::post().to(impersonate_stop::<App1>))).await
+    };
+}
+
+// the auth middleware fails the request instead of answering it
+fn status(res: Result<ServiceResponse, actix_web::Error>) -> StatusCode {
+    match res {
+        Ok(it) => it.status(),
+        Err(e) => e.as_response_error().status_code(),
+    }
+}
+
+fn start(code: &str, auth_kind: &str, principal: &str) -> test::TestRequest {
+    test::TestRequest::post().uri("/impersonate")
+        .cookie(Cookie::new(SESSION_COOKIE_NAME, code.to_string()))
+        .set_json(serde_json::json!({ "auth_kind": auth_kind, "principal": principal }))
+}
+
+fn get(uri: &str, code: &str) -> test::TestRequest {
+    test::TestRequest::get().uri(uri).cookie(Cookie::new(SESSION_COOKIE_NAME, code.to_string()))
+}
+
+#[actix_web::test]
+async fn impersonation_is_audited_and_never_extended() {
+    let (db, objs) = setup("impersonate_audit").await;
+    let app = app!(db, objs);
+    let admin = login(&db, &objs, ADMIN).await;
+
+    let res = app.call(start(&admin, "Oidc", USER).to_request()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let code = res.response().cookies().find(|it| it.name() == SESSION_COOKIE_NAME).unwrap().value().to_string();
+    let started = objs.advance(0);
+    assert_eq!(audit_count(&db, ADMIN).await, 1);
+
+    objs.advance(60);
+    for _ in 0..2 {
+        let res = app.call(get("/whoami", &code).to_request()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(test::read_body(res).await, format!("{USER}:{ADMIN}"));
+    }
+    assert_eq!(audit_count(&db, ADMIN).await, 3);
+    assert_eq!(session(&db, &code).await.unwrap().expires, started + IMPERSONATION_LIFETIME);
+
+    // normal sessions are extended
+    let now = objs.advance(0);
+    app.call(get("/whoami", &admin).to_request()).await.unwrap();
+    assert_eq!(session(&db, &admin).await.unwrap().expires, now + 3600);
+    assert_eq!(audit_count(&db, ADMIN).await, 3);
+}
+
+#[actix_web::test]
+async fn admins_can_not_be_impersonated() {
+    let (db, objs) = setup("impersonate_admin").await;
+    let app = app!(db, objs);
+    let admin = login(&db, &objs, ADMIN).await;
+
+    assert_eq!(status(app.call(start(&admin, "Oidc", OTHER_ADMIN).to_request()).await), StatusCode::UNAUTHORIZED);
+    assert_eq!(audit_count(&db, ADMIN).await, 0);
+
+    // an impersonation session of an admin made some other way still doesn't pass PrincipalAdmin
+    let target = PrincipalInner { auth_kind: "Oidc".into(), principal: OTHER_ADMIN.into(), parent: None, impersonator: Some(ADMIN.into()) };
+    let sess = TestSession::new_impersonation(objs.advance(0) + 60, target, admin.clone());
+    let mut txn = db.newtx_write().await.unwrap();
+    TestSession::insert(&mut txn, &sess).await.unwrap();
+    txn.commit().await.unwrap();
+    assert_eq!(status(app.call(get("/admin", &sess.code).to_request()).await), StatusCode::BAD_REQUEST);
+    assert_eq!(status(app.call(get("/admin", &admin).to_request()).await), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn no_chaining_and_stop_needs_an_impersonation() {
+    let (db, objs) = setup("impersonate_stop").await;
+    let app = app!(db, objs);
+    let admin = login(&db, &objs, ADMIN).await;
+
+    let res = app.call(test::TestRequest::post().uri("/impersonate/stop")
+        .cookie(Cookie::new(SESSION_COOKIE_NAME, admin.clone())).to_request()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert!(session(&db, &admin).await.is_some());
+
+    let res = app.call(start(&admin, "Oidc", USER).to_request()).await.unwrap();
+    let code = res.response().cookies().find(|it| it.name() == SESSION_COOKIE_NAME).unwrap().value().to_string();
+
+    // chaining, already stopped by PrincipalAdmin for impersonation sessions
+    assert_ne!(status(app.call(start(&code, "Oidc", "third@bear.test").to_request()).await), StatusCode::OK);
+    let mut txn = db.newtx_read().await.unwrap();
+    let sessions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM sessions").fetch_one(&mut *txn).await.unwrap();
+    drop(txn);
+    assert_eq!(sessions, 2);
+
+    let res = app.call(test::TestRequest::post().uri("/impersonate/stop")
+        .cookie(Cookie::new(SESSION_COOKIE_NAME, code.clone())).to_request()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(session(&db, &code).await.is_none());
+    assert!(session(&db, &admin).await.is_some());
+    // the admin is back in their own session
+    let cookie = res.response().cookies().find(|it| it.name() == SESSION_COOKIE_NAME).unwrap().value().to_string();
+    assert_eq!(cookie, admin);
+    let res = app.call(get("/whoami", &cookie).to_request()).await.unwrap();
+    assert_eq!(test::read_body(res).await, format!("{ADMIN}:"));
+}
+
+#[actix_web::test]
+async fn the_target_keeps_its_parent() {
+    let (db, objs) = setup("impersonate_parent").await;
+    let app = app!(db, objs);
+    let admin = login(&db, &objs, ADMIN).await;
+
+    let req = test::TestRequest::post().uri("/impersonate")
+        .cookie(Cookie::new(SESSION_COOKIE_NAME, admin.clone()))
+        .set_json(serde_json::json!({ "auth_kind": "Device", "principal": "device-1", "parent": "shop-7" }));
+    let res = app.call(req.to_request()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let code = res.response().cookies().find(|it| it.name() == SESSION_COOKIE_NAME).unwrap().value().to_string();
+    assert_eq!(session(&db, &code).await.unwrap().admin_session, Some(admin));
+
+    let res = app.call(get("/parent", &code).to_request()).await.unwrap();
+    assert_eq!(test::read_body(res).await, "shop-7");
+}
@@ -0,0 +1,128 @@
+// Database maintenance tasks and the admin handler, sqlite only: they work on the database file.
+#![cfg(feature = "sqlite")]
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use bear::db::{db_init, DbMain};
+use bear::maintenance::*;
+use sqlx::{Connection, Executor, SqliteConnection};
+
+fn path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("bear-maintenance-{}-{name}.db", std::process::id()));
+    for it in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{it}", path.display()));
+    }
+    path
+}
+
+// `setup` runs on the new file before bear opens it
+async fn open(path: &Path, setup: &str) -> DbMain {
+    let url = format!("sqlite:{}", path.display());
+    let mut conn = SqliteConnection::connect(&format!("{url}?mode=rwc")).await.unwrap();
+    conn.execute(setup).await.unwrap();
+    conn.close().await.unwrap();
+    let db: DbMain = db_init(&url, &sqlx::migrate::Migrator::DEFAULT).await.unwrap();
+    let mut txn = db.newtx_write().await.unwrap();
+    txn.conn().execute("CREATE TABLE docs (id INTEGER PRIMARY KEY, body TEXT NOT NULL); \
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2000) \
+        INSERT INTO docs (body) SELECT printf('%.500c', 'x') FROM n").await.unwrap();
+    txn.commit().await.unwrap();
+    db
+}
+
+async fn run(db: &DbMain, task: MaintenanceTask, settings: &MaintenanceSettings) -> String {
+    run_task(db, task, settings).await.unwrap().detail
+}
+
+#[tokio::test]
+async fn checkpoint_optimize_quick_check() {
+    let path = path("tasks");
+    let db = open(&path, "SELECT 1").await;
+    let settings = MaintenanceSettings::default();
+    let wal = PathBuf::from(format!("{}-wal", path.display()));
+    assert!(std::fs::metadata(&wal).unwrap().len() > 0);
+
+    assert!(run(&db, MaintenanceTask::Checkpoint, &settings).await.starts_with("busy=0 "));
+    // truncated, not only checkpointed
+    assert_eq!(std::fs::metadata(&wal).unwrap().len(), 0);
+
+    assert_eq!(run(&db, MaintenanceTask::Optimize, &settings).await, "ok");
+    assert_eq!(run(&db, MaintenanceTask::QuickCheck, &settings).await, "ok");
+}
+
+async fn free_pages(db: &DbMain) {
+    let mut txn = db.newtx_write().await.unwrap();
+    txn.conn().execute("DELETE FROM docs").await.unwrap();
+    txn.commit().await.unwrap();
+}
+
+#[tokio::test]
+async fn incremental_vacuum_needs_incremental_auto_vacuum() {
+    let settings = MaintenanceSettings { vacuum_pages: 0, ..Default::default() };
+
+    let db = open(&path("vacuum_none"), "SELECT 1").await;
+    free_pages(&db).await;
+    let detail = run(&db, MaintenanceTask::IncrementalVacuum, &settings).await;
+    assert!(detail.starts_with("skipped, auto_vacuum=0 freelist_pages="), "{detail}");
+    assert_ne!(detail, "skipped, auto_vacuum=0 freelist_pages=0");
+
+    // only takes effect before the first table
+    let db = open(&path("vacuum_incremental"), "PRAGMA auto_vacuum = INCREMENTAL").await;
+    free_pages(&db).await;
+    let detail = run(&db, MaintenanceTask::IncrementalVacuum, &settings).await;
+    assert!(detail.starts_with("freelist_pages=") && detail.ends_with("->0"), "{detail}");
+    assert_ne!(detail, "freelist_pages=0->0");
+}
+
+#[cfg(not(feature = "postgres"))]
+mod handler {
+    use actix_web::{test, web, App};
+    use actix_web::cookie::Cookie;
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::http::StatusCode;
+    use bear::authmw::AuthMidFactory;
+    use bear::maintenance::{maintenance_run, MaintenanceSettings};
+    use bear::oidc::SESSION_COOKIE_NAME;
+    use crate::common::{login, test_db, TestApp, TestSession, SESSIONS_TABLE};
+
+    type App1 = TestApp<TestSession>;
+
+    const ADMIN: &str = "admin@bear.test";
+    const USER: &str = "user@bear.test";
+
+    // the auth middleware fails the request instead of answering it
+    fn status(res: Result<ServiceResponse, actix_web::Error>) -> StatusCode {
+        match res {
+            Ok(it) => it.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn maintenance_run_is_admin_only() {
+        let db = test_db("maintenance_run", &[SESSIONS_TABLE]).await;
+        let objs = TestApp::with_sessions(&[ADMIN]);
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(db.clone()))
+            .app_data(web::Data::new(MaintenanceSettings::default()))
+            .app_data(objs.clone())
+            .wrap(AuthMidFactory::<App1>::new(db.clone(), false))
+            .route("/admin/db/maintenance/{task}", web::post().to(maintenance_run::<App1>))).await;
+        let run = |code: &str| test::TestRequest::post().uri("/admin/db/maintenance/quick_check")
+            .cookie(Cookie::new(SESSION_COOKIE_NAME, code.to_string()))
+            .to_request();
+
+        let user = login(&db, &objs, USER).await;
+        assert_eq!(status(app.call(run(&user)).await), StatusCode::BAD_REQUEST);
+        // without a session
+        assert_ne!(status(app.call(run("")).await), StatusCode::OK);
+
+        let admin = login(&db, &objs, ADMIN).await;
+        let res = app.call(run(&admin)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let report: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(report["task"], "quick_check");
+        assert_eq!(report["detail"], "ok");
+    }
+}
@@ -0,0 +1,80 @@
+// Metrics recorded by the transaction extractors, read back with the per thread debugging recorder.
+
+mod common;
+
+use actix_web::{test, web, App, HttpResponse};
+use actix_web::dev::Service;
+use bear::db::*;
+use bear::errors::AnyHandlerError;
+use bear::txnmw::{ReadThenWriteTxn, ReadTxn, TxnMidFactory, WriteTxn};
+use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+use common::{doc, test_db, Doc, TestBackend, DOCS_TABLE};
+
+// histogram, route label and number of samples since the last call on this thread, a snapshot empties
+// the histograms
+fn acquire_samples() -> Vec<(String, String, usize)> {
+    let mut samples: Vec<(String, String, usize)> = Snapshotter::current_thread_snapshot().unwrap().into_vec().into_iter()
+        .filter(|(key, ..)| key.key().name().ends_with("_acquire_seconds"))
+        .filter_map(|(key, _, _, value)| {
+            let route = key.key().labels().find(|it| it.key() == "route")?.value().to_string();
+            match value {
+                DebugValue::Histogram(values) if !values.is_empty() => Some((key.key().name().to_string(), route, values.len())),
+                _ => None,
+            }
+        })
+        .collect();
+    samples.sort();
+    samples
+}
+
+fn sample(name: &str, route: &str, n: usize) -> (String, String, usize) {
+    (String::from(name), String::from(route), n)
+}
+
+async fn get_doc(mut txn: ReadTxn<'_, TestBackend>, id: web::Path<String>) -> Result<HttpResponse, AnyHandlerError> {
+    let found = find_by_id::<Doc, _>(txn.get(), &id).await?;
+    Ok(if found.is_some() { HttpResponse::Ok().finish() } else { HttpResponse::NotFound().finish() })
+}
+
+async fn put_doc(mut txn: WriteTxn<'_, TestBackend>, id: web::Path<String>) -> Result<HttpResponse, AnyHandlerError> {
+    insert(txn.get(), &doc(&id, 0)).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn copy_doc(mut txn: ReadThenWriteTxn<'_, TestBackend>, id: web::Path<String>) -> Result<HttpResponse, AnyHandlerError> {
+    if find_by_id::<Doc, _>(txn.read().await?, &id).await?.is_none() {
+        return Ok(HttpResponse::NotFound().finish())
+    }
+    insert(txn.write().await?, &doc(&format!("{id}-copy"), 0)).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[actix_web::test]
+async fn acquire_times_by_route() {
+    // per thread, the other tests in the binary don't show up. Installing again only fails.
+    let _ = DebuggingRecorder::per_thread().install();
+    let db = test_db("metrics_acquire", &[DOCS_TABLE]).await;
+    let app = test::init_service(App::new()
+        .app_data(web::Data::new(db.clone()))
+        .wrap(TxnMidFactory::new(db.clone()))
+        .route("/docs/{id}", web::get().to(get_doc))
+        .route("/docs/{id}", web::put().to(put_doc))
+        .route("/docs/{id}/copy", web::post().to(copy_doc))).await;
+    acquire_samples();
+
+    for id in ["a", "b"] {
+        app.call(test::TestRequest::put().uri(&format!("/docs/{id}")).to_request()).await.unwrap();
+    }
+    app.call(test::TestRequest::get().uri("/docs/a").to_request()).await.unwrap();
+    app.call(test::TestRequest::post().uri("/docs/a/copy").to_request()).await.unwrap();
+    // outside of requests
+    db.newtx_read().await.unwrap().rollback().await.unwrap();
+
+    assert_eq!(acquire_samples(), [
+        sample("db_reader_acquire_seconds", "/docs/{id}", 1),
+        sample("db_reader_acquire_seconds", "/docs/{id}/copy", 1),
+        sample("db_reader_acquire_seconds", "job", 1),
+        sample("db_writer_acquire_seconds", "/docs/{id}", 2),
+        sample("db_writer_acquire_seconds", "/docs/{id}/copy", 1),
+    ]);
+}
@@ -0,0 +1,175 @@
+// Outbox dispatcher: retries with backoff, dead letters and claiming.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use bear::backend::BackendSql;
+use bear::db::DbMain;
+use bear::outbox::*;
+use common::{test_db, TestApp, TestBackend};
+
+struct Flaky {
+    fail: AtomicBool,
+    calls: AtomicU32,
+}
+
+#[async_trait]
+impl OutboxHandler for Flaky {
+    async fn deliver(&self, _msg: &OutboxMessage) -> anyhow::Result<()> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        if self.fail.load(Ordering::SeqCst) { anyhow::bail!("receiver down") }
+        Ok(())
+    }
+}
+
+fn flaky(fail: bool) -> Arc<Flaky> {
+    Arc::new(Flaky { fail: AtomicBool::new(fail), calls: AtomicU32::new(0) })
+}
+
+fn settings() -> OutboxSettings {
+    OutboxSettings { max_attempts: 3, backoff_secs: 10, max_backoff_secs: 15, lease_secs: 60, ..Default::default() }
+}
+
+async fn put(db: &DbMain<TestBackend>, now: i64) -> i64 {
+    let mut txn = db.newtx_write().await.unwrap();
+    let id = outbox_put(&mut txn, "mail", &serde_json::json!({ "to": "someone" }), now).await.unwrap();
+    txn.commit().await.unwrap();
+    id
+}
+
+// status, attempts, next_attempt
+async fn state(db: &DbMain<TestBackend>, id: i64) -> (String, i64, i64) {
+    let mut txn = db.newtx_read().await.unwrap();
+    sqlx::query_as("SELECT status, attempts, next_attempt FROM outbox WHERE id = $1")
+        .bind(id)
+        .fetch_one(&mut *txn).await.unwrap()
+}
+
+#[tokio::test]
+async fn retries_with_backoff_then_dead() {
+    let db = test_db("outbox_retry", &[OUTBOX_SCHEMA.on::<TestBackend>()]).await;
+    let objs = TestApp::new();
+    let handler = flaky(true);
+    let dispatcher = OutboxDispatcher::new(db.clone(), objs.clone().into_inner(), settings())
+        .register("mail", handler.clone());
+    let id = put(&db, objs.advance(0)).await;
+
+    let t0 = objs.advance(0);
+    assert_eq!(dispatcher.dispatch_once().await.unwrap(), 1);
+    assert_eq!(state(&db, id).await, (OUTBOX_PENDING.into(), 1, t0 + 10));
+    // not due yet
+    assert_eq!(dispatcher.dispatch_once().await.unwrap(), 0);
+
+    let t1 = objs.advance(10);
+    assert_eq!(dispatcher.dispatch_once().await.unwrap(), 1);
+    assert_eq!(state(&db, id).await, (OUTBOX_PENDING.into(), 2, t1 + 15));
+
+    let t2 = objs.advance(15);
+    assert_eq!(dispatcher.dispatch_once().await.unwrap(), 1);
+    assert_eq!(state(&db, id).await, (OUTBOX_DEAD.into(), 3, t2 + 15));
+
+    objs.advance(1000);
+    assert_eq!(dispatcher.dispatch_once().await.unwrap(), 0);
+    assert_eq!(handler.calls.load(Ordering::SeqCst), 3);
+
+    handler.fail.store(false, Ordering::SeqCst);
+    let mut txn = db.newtx_write().await.unwrap();
+    assert_eq!(outbox_retry_dead(&mut txn, objs.advance(0)).await.unwrap(), 1);
+    txn.commit().await.unwrap();
+    assert_eq!(dispatcher.dispatch_once().await.unwrap(), 1);
+    assert_eq!(state(&db, id).await.0, OUTBOX_DELIVERED);
+    assert_eq!(state(&db, id).await.1, 1);
+}
+
+// delivers through another dispatcher on the same database while its own message is claimed, then fails late
+struct Nested {
+    other: Mutex<Option<OutboxDispatcher<TestBackend>>>,
+    objs: actix_web::web::Data<TestApp>,
+    seen: Mutex<Vec<usize>>,
+}
+
+#[async_trait]
+impl OutboxHandler for Nested {
+    async fn deliver(&self, _msg: &OutboxMessage) -> anyhow::Result<()> {
+        let other = self.other.lock().unwrap().take().unwrap();
+        let during_lease = other.dispatch_once().await?;
+        self.objs.advance(61);
+        let after_lease = other.dispatch_once().await?;
+        self.seen.lock().unwrap().extend([during_lease, after_lease]);
+        anyhow::bail!("too late")
+    }
+}
+
+#[tokio::test]
+async fn claimed_messages_are_skipped_until_the_lease_ends() {
+    let db = test_db("outbox_claim", &[OUTBOX_SCHEMA.on::<TestBackend>()]).await;
+    let objs = TestApp::new();
+    let other_handler = flaky(false);
+    let other = OutboxDispatcher::new(db.clone(), objs.clone().into_inner(), settings())
+        .register("mail", other_handler.clone());
+    let nested = Arc::new(Nested { other: Mutex::new(Some(other)), objs: objs.clone(), seen: Mutex::new(vec![]) });
+    let dispatcher = OutboxDispatcher::new(db.clone(), objs.clone().into_inner(), settings())
+        .register("mail", nested.clone());
+    let id = put(&db, objs.advance(0)).await;
+
+    assert_eq!(dispatcher.dispatch_once().await.unwrap(), 1);
+    // in flight for the first dispatcher, then delivered again once its lease ran out
+    assert_eq!(*nested.seen.lock().unwrap(), [0, 1]);
+    assert_eq!(other_handler.calls.load(Ordering::SeqCst), 1);
+    // the first dispatcher's failure came after its lease and doesn't overwrite the delivery
+    let (status, attempts, _) = state(&db, id).await;
+    assert_eq!((status.as_str(), attempts), (OUTBOX_DELIVERED, 1));
+}
+
+// the result for message 1 can't be written
+const REFUSE_RECORD: BackendSql = BackendSql {
+    sqlite: "CREATE TRIGGER outbox_refuse BEFORE UPDATE ON outbox WHEN NEW.id = 1 AND NEW.status <> 'in_flight' \
+        BEGIN SELECT RAISE(ABORT, 'refused'); END",
+    postgres: "CREATE FUNCTION outbox_refuse() RETURNS trigger AS $$ BEGIN RAISE EXCEPTION 'refused'; END $$ LANGUAGE plpgsql; \
+        CREATE TRIGGER outbox_refuse BEFORE UPDATE ON outbox FOR EACH ROW WHEN (NEW.id = 1 AND NEW.status <> 'in_flight') \
+        EXECUTE FUNCTION outbox_refuse()",
+};
+
+#[tokio::test]
+async fn unrecorded_results_dont_stop_the_batch() {
+    let db = test_db("outbox_unrecorded", &[OUTBOX_SCHEMA.on::<TestBackend>(), REFUSE_RECORD.on::<TestBackend>()]).await;
+    let objs = TestApp::new();
+    let handler = flaky(false);
+    let dispatcher = OutboxDispatcher::new(db.clone(), objs.clone().into_inner(), settings())
+        .register("mail", handler.clone());
+    let first = put(&db, objs.advance(0)).await;
+    let second = put(&db, objs.advance(0)).await;
+    assert_eq!(first, 1);
+
+    let t0 = objs.advance(0);
+    assert_eq!(dispatcher.dispatch_once().await.unwrap(), 2);
+    assert_eq!(handler.calls.load(Ordering::SeqCst), 2);
+    // claimed until the lease runs out, then delivered again
+    assert_eq!(state(&db, first).await, (OUTBOX_IN_FLIGHT.into(), 0, t0 + 60));
+    assert_eq!(state(&db, second).await.0, OUTBOX_DELIVERED);
+}
+
+#[tokio::test]
+async fn http_delivery_times_out_within_the_lease() {
+    // accepts and never answers
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let mut open = vec![];
+        loop {
+            open.push(listener.accept().await.unwrap().0);
+        }
+    });
+
+    let settings = OutboxSettings { lease_secs: 4, batch_size: 2, ..Default::default() };
+    assert_eq!(settings.delivery_timeout(), Duration::from_secs(2));
+    let handler = HttpOutboxHandler::new(&format!("http://{addr}/hook"), &settings).unwrap();
+    let msg = OutboxMessage { id: 1, topic: "mail".into(), payload: "{}".into(), attempts: 0, created: 0, lease: 4 };
+    let start = Instant::now();
+    assert!(handler.deliver(&msg).await.is_err());
+    assert!(start.elapsed() < Duration::from_secs(4), "{:?}", start.elapsed());
+    server.abort();
+}
@@ -0,0 +1,67 @@
+// Keyset pagination, cursors and the page params.
+
+mod common;
+
+use bear::errors::ApiError;
+use bear::page::*;
+use bear::db::*;
+use bear::query::{fetch_page, Dir, Select};
+use common::{test_db, Doc, TestBackend, DOCS_TABLE};
+
+#[test]
+fn cursor_round_trip() {
+    for key in [KeyValue::Int(-7), KeyValue::Real(1.5), KeyValue::Text(String::from("a b/ü"))] {
+        let cursor = Cursor { key, id: String::from("ord_1") };
+        assert_eq!(Cursor::decode(&cursor.encode()).unwrap(), cursor);
+    }
+    for bad in ["", "0OIl", "3mJr7AoUXx2Wqd"] {
+        let err = Cursor::decode(bad).unwrap_err();
+        assert_eq!(err.downcast_ref::<ApiError>(), Some(&ApiError::Any(String::from("cursor.invalid"))), "{bad}");
+    }
+}
+
+#[test]
+fn page_params_clamped() {
+    let limit = |query: &str| PageParams::parse(query).unwrap().limit;
+    assert_eq!(limit(""), DEFAULT_PAGE_LIMIT);
+    assert_eq!(limit("limit=5"), 5);
+    assert_eq!(limit("limit=0"), 1);
+    assert_eq!(limit("limit=-3"), 1);
+    assert_eq!(limit("limit=1000"), MAX_PAGE_LIMIT);
+
+    let cursor = Cursor { key: KeyValue::Int(1), id: String::from("a") };
+    assert_eq!(PageParams::parse(&format!("cursor={}", cursor.encode())).unwrap().cursor, Some(cursor));
+    assert_eq!(PageParams::parse("cursor=").unwrap().cursor, None);
+    assert!(PageParams::parse("limit=many").is_err());
+    assert!(PageParams::parse("cursor=0OIl").is_err());
+}
+
+// all ids, following the cursors 2 rows at a time
+async fn walk(db: &DbMain<TestBackend>, dir: Dir) -> Vec<String> {
+    let mut txn = db.newtx_read().await.unwrap();
+    let mut params = PageParams::parse("limit=2").unwrap();
+    let mut ids = Vec::new();
+    loop {
+        let page = fetch_page(&mut txn, Select::<Doc, _>::new(), "version", dir, &params, |it| (it.version.into(), it.id.clone())).await.unwrap();
+        assert!(page.items.len() <= 2);
+        ids.extend(page.items.into_iter().map(|it| it.id));
+        match page.next {
+            Some(next) => params = PageParams::parse(&format!("cursor={next}&limit=2")).unwrap(),
+            None => return ids,
+        }
+    }
+}
+
+#[tokio::test]
+async fn equal_sort_keys_are_paged_by_id() {
+    let db = test_db("page_ties", &[DOCS_TABLE]).await;
+    let mut txn = db.newtx_write().await.unwrap();
+    // pages of 2 end between b and c, and between d and e, in both directions
+    for (id, version) in [("c", 1), ("a", 1), ("e", 2), ("b", 1), ("f", 3), ("d", 2)] {
+        insert(&mut txn, &common::doc(id, version)).await.unwrap();
+    }
+    txn.commit().await.unwrap();
+
+    assert_eq!(walk(&db, Dir::Asc).await, ["a", "b", "c", "d", "e", "f"]);
+    assert_eq!(walk(&db, Dir::Desc).await, ["f", "e", "d", "c", "b", "a"]);
+}
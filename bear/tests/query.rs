@@ -0,0 +1,125 @@
+// The query builder: the sql it writes, the placeholder numbering and the rows that come back.
+
+mod common;
+
+use bear::backend::Backend;
+use bear::db::*;
+use bear::page::{KeyValue, PageParams};
+use bear::query::{col, fetch_page, Cond, Dir, Select};
+use common::{doc, test_db, Doc, TestBackend, DOCS_TABLE};
+
+type DocSelect<'q> = Select<'q, Doc, TestBackend>;
+
+fn sql(select: DocSelect<'_>) -> String {
+    select.to_sql().unwrap()
+}
+
+#[test]
+fn grouping() {
+    // or inside and, the groups keep their parentheses
+    let select = DocSelect::new()
+        .filter(col("body").eq("x").or(col("version").gt(1i64)).and(col("id").ne("z")))
+        .with_deleted();
+    assert_eq!(sql(select), "SELECT * FROM docs WHERE (((body = $1 OR version > $2) AND id <> $3))");
+
+    let select = DocSelect::new()
+        .filter(Cond::any(vec![col("id").eq("a"), Cond::all(vec![col("id").eq("b"), col("version").lt(3i64)])]))
+        .filter(col("body").like("b%"));
+    assert_eq!(sql(select), "SELECT * FROM docs WHERE ((id = $1 OR (id = $2 AND version < $3)) AND body LIKE $4 AND deleted_at IS NULL)");
+
+    // empty groups are neutral for and, match nothing for or
+    let select = DocSelect::new().filter(Cond::all(vec![])).filter(Cond::any(vec![])).with_deleted();
+    assert_eq!(sql(select), "SELECT * FROM docs WHERE (1 = 1 AND 1 = 0)");
+}
+
+#[test]
+fn conditions() {
+    let select = DocSelect::new()
+        .filter(col("id").is_in(Vec::<&str>::new()))
+        .filter(col("version").between(2i64, 4i64))
+        .filter(col("deleted_at").not_null())
+        .with_deleted();
+    assert_eq!(sql(select), "SELECT * FROM docs WHERE (1 = 0 AND version BETWEEN $1 AND $2 AND deleted_at IS NOT NULL)");
+
+    let select = DocSelect::new().filter(col("deleted_at").is_null()).with_deleted();
+    assert_eq!(sql(select), "SELECT * FROM docs WHERE (deleted_at IS NULL)");
+}
+
+#[test]
+fn placeholders_follow_the_sql() {
+    let select = DocSelect::new()
+        .filter(col("body").ne("x"))
+        .filter(col("id").is_in(["a", "b", "c"]))
+        .filter(col("version").between(1i64, 9i64).or(col("version").eq(20i64)))
+        .order_by("version", Dir::Desc)
+        .limit(5);
+    assert_eq!(sql(select), "SELECT * FROM docs WHERE (body <> $1 AND id IN ($2, $3, $4) AND (version BETWEEN $5 AND $6 OR version = $7) \
+        AND deleted_at IS NULL) ORDER BY version DESC LIMIT 5");
+}
+
+#[test]
+fn offset_without_limit() {
+    let select = DocSelect::new().order_by("id", Dir::Asc).offset(2);
+    assert_eq!(sql(select), format!("SELECT * FROM docs WHERE (deleted_at IS NULL) ORDER BY id ASC LIMIT {} OFFSET 2", TestBackend::NO_LIMIT));
+}
+
+async fn fill(name: &str) -> DbMain<TestBackend> {
+    let db = test_db(name, &[DOCS_TABLE]).await;
+    let mut txn = db.newtx_write().await.unwrap();
+    for (id, version) in [("a", 1), ("b", 2), ("c", 3), ("d", 4), ("e", 5)] {
+        insert(&mut txn, &doc(id, version)).await.unwrap();
+    }
+    soft_delete::<Doc, _>(&mut txn, "e", 100).await.unwrap();
+    txn.commit().await.unwrap();
+    db
+}
+
+fn ids(rows: Vec<Doc>) -> Vec<String> {
+    rows.into_iter().map(|it| it.id).collect()
+}
+
+#[tokio::test]
+async fn fetch_and_count() {
+    let db = fill("query_fetch").await;
+    let mut txn = db.newtx_read().await.unwrap();
+
+    // the values reach the placeholders they were numbered for
+    let rows = DocSelect::new()
+        .filter(col("body").ne("body a"))
+        .filter(col("id").is_in(["a", "b", "c", "e"]))
+        .filter(col("version").between(3i64, 9i64).or(col("id").eq("b")))
+        .order_by("version", Dir::Desc)
+        .fetch_all(&mut txn).await.unwrap();
+    assert_eq!(ids(rows), ["c", "b"]);
+
+    assert_eq!(DocSelect::new().filter(col("id").is_in(Vec::<&str>::new())).fetch_all(&mut txn).await.unwrap(), []);
+    let rows = DocSelect::new().filter(col("deleted_at").not_null()).with_deleted().fetch_all(&mut txn).await.unwrap();
+    assert_eq!(ids(rows), ["e"]);
+
+    let rows = DocSelect::new().order_by("id", Dir::Asc).offset(2).fetch_all(&mut txn).await.unwrap();
+    assert_eq!(ids(rows), ["c", "d"]);
+
+    // order, limit and offset don't change the count, the soft deleted row does
+    let shaped = |select: DocSelect<'static>| select.order_by("id", Dir::Asc).limit(1).offset(1);
+    assert_eq!(shaped(DocSelect::new()).count(&mut txn).await.unwrap(), 4);
+    assert_eq!(shaped(DocSelect::new().with_deleted()).count(&mut txn).await.unwrap(), 5);
+    assert_eq!(shaped(DocSelect::new().filter(col("version").ge(3i64))).count(&mut txn).await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn filtered_pages() {
+    let db = fill("query_pages").await;
+    let mut txn = db.newtx_read().await.unwrap();
+    let select = || DocSelect::new().filter(col("version").ne(2i64)).filter(col("body").like("body %"));
+    let key = |it: &Doc| (KeyValue::Text(it.body.clone()), it.id.clone());
+
+    let params = PageParams::parse("limit=2").unwrap();
+    let page = fetch_page(&mut txn, select(), "body", Dir::Asc, &params, key).await.unwrap();
+    assert_eq!(ids(page.items), ["a", "c"]);
+
+    // the cursor's arguments come after the filter's
+    let params = PageParams::parse(&format!("cursor={}&limit=2", page.next.unwrap())).unwrap();
+    let page = fetch_page(&mut txn, select(), "body", Dir::Asc, &params, key).await.unwrap();
+    assert_eq!(ids(page.items), ["d"]);
+    assert_eq!(page.next, None);
+}
@@ -0,0 +1,66 @@
+// Soft deleted rows: left alone by the update helpers, hard deleted by Retention once expired.
+
+mod common;
+
+use bear::errors::ApiError;
+use bear::db::*;
+use bear::retention::Retention;
+use common::{doc, test_db, Doc, TestApp, TestBackend, DOCS_TABLE};
+
+#[tokio::test]
+async fn deleted_rows_are_not_updated() -> anyhow::Result<()> {
+    let db = test_db("soft_delete_updates", &[DOCS_TABLE]).await;
+    let mut txn = db.newtx_write().await?;
+    insert(&mut txn, &doc("gone", 0)).await?;
+    assert!(soft_delete::<Doc, _>(&mut txn, "gone", 100).await?);
+
+    assert!(!update_field::<Doc, _, _>(&mut txn, "body", &"changed", "gone").await?);
+    assert_eq!(update_fields::<Doc, _>(&mut txn, "gone", Assignments::new().set("body", "changed")).await?, 0);
+    let err = update_fields_versioned::<Doc, _>(&mut txn, "gone", 0, Assignments::new().set("body", "changed")).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<ApiError>(), Some(&ApiError::NotFound(String::from("docs"))));
+
+    let row = find_by_id_with_deleted::<Doc, _>(&mut txn, "gone").await?.unwrap();
+    assert_eq!((row.body.as_str(), row.version), ("body gone", 0));
+
+    // restored, it can be changed again
+    assert!(restore::<Doc, _>(&mut txn, "gone").await?);
+    assert_eq!(update_fields_versioned::<Doc, _>(&mut txn, "gone", 0, Assignments::new().set("body", "changed")).await?, 1);
+    txn.rollback().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn retention_removes_expired_rows() -> anyhow::Result<()> {
+    let db = test_db("soft_delete_retention", &[DOCS_TABLE]).await;
+    let app = TestApp::new();
+    let retention = Retention::new(db.clone(), app.clone()).keep::<Doc>(1000)?;
+
+    let mut txn = db.newtx_write().await?;
+    for id in ["early", "late", "live"] {
+        insert(&mut txn, &doc(id, 0)).await?;
+    }
+    assert!(soft_delete::<Doc, _>(&mut txn, "early", app.advance(0)).await?);
+    assert!(soft_delete::<Doc, _>(&mut txn, "late", app.advance(500)).await?);
+    txn.commit().await?;
+
+    let remaining = |db: DbMain<TestBackend>| async move {
+        let mut txn = db.newtx_read().await.unwrap();
+        let mut ids: Vec<String> = sqlx::query_scalar("SELECT id FROM docs").fetch_all(&mut *txn).await.unwrap();
+        ids.sort();
+        ids
+    };
+
+    // `early` was deleted at 1000, `late` at 1500, both are kept for 1000s
+    app.advance(500);
+    assert_eq!(retention.run_once().await?, 0);
+    app.advance(1);
+    assert_eq!(retention.run_once().await?, 1);
+    assert_eq!(remaining(db.clone()).await, ["late", "live"]);
+
+    app.advance(499);
+    assert_eq!(retention.run_once().await?, 0);
+    app.advance(10_000);
+    assert_eq!(retention.run_once().await?, 1);
+    assert_eq!(remaining(db.clone()).await, ["live"]);
+    Ok(())
+}
@@ -0,0 +1,359 @@
+// Transaction middleware and the extractors, run with --features test-support.
+
+mod common;
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use actix_web::{test, web, App, HttpRequest, HttpResponse};
+use actix_web::dev::Service;
+use actix_web::http::StatusCode;
+use actix_web::cookie::Cookie;
+use bear::errors::AnyHandlerError;
+use bear::audit::{REQUEST_ID_HEADER, ROW_AUDIT_SCHEMA};
+use bear::authmw::{AuthMidFactory, PrincipalAny, PrincipalInner};
+use bear::interface::Session;
+use bear::impersonate::IMPERSONATION_AUDIT_SCHEMA;
+use bear::oidc::SESSION_COOKIE_NAME;
+use bear::db::*;
+use bear::testbase::{expect_committed, expect_no_txn, expect_rolled_back, handler_with_tx_logged};
+use bear::txnmw::{decide_txn, ReadThenWriteTxn, RetryPolicy, TxnDecision, TxnLog, TxnMidFactory, TxnState, WriteTxn};
+use common::{doc, login, test_db, Doc, TestApp, TestBackend, TestSession, DOCS_TABLE, SESSIONS_TABLE};
+
+#[derive(Default)]
+struct Calls {
+    busy: u32, // the first `busy` calls fail busy
+    handler: AtomicU32,
+    rolled_back: AtomicU32, // after_rollback hooks run
+    committed: AtomicU32, // after_commit hooks run
+}
+
+fn calls(busy: u32) -> web::Data<Calls> {
+    web::Data::new(Calls { busy, ..Default::default() })
+}
+
+async fn put_doc(
+    mut txn: WriteTxn<'_, TestBackend>,
+    id: web::Path<String>,
+    body: String,
+    calls: web::Data<Calls>,
+) -> Result<HttpResponse, AnyHandlerError> {
+    let hook_calls = calls.clone();
+    txn.after_rollback(async move {
+        hook_calls.rolled_back.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    });
+    insert(txn.get(), &Doc { id: id.into_inner(), body, version: 0, deleted_at: None }).await?;
+    if calls.handler.fetch_add(1, Ordering::SeqCst) < calls.busy {
+        return Err(sqlx::Error::PoolTimedOut.into())
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+// the outcome is picked by the id
+async fn write_doc(mut txn: WriteTxn<'_, TestBackend>, id: web::Path<String>, req: HttpRequest) -> Result<HttpResponse, AnyHandlerError> {
+    insert(txn.get(), &doc(&id, 0)).await?;
+    Ok(match id.as_str() {
+        "rejected" => HttpResponse::BadRequest().finish(),
+        "kept" => {
+            decide_txn(&req, TxnDecision::Commit);
+            HttpResponse::BadRequest().finish()
+        },
+        "discarded" => {
+            let mut res = HttpResponse::Ok().finish();
+            res.extensions_mut().insert(TxnDecision::Rollback);
+            res
+        },
+        _ => HttpResponse::Ok().finish()
+    })
+}
+
+fn retry() -> RetryPolicy {
+    RetryPolicy { backoff_ms: 1, max_backoff_ms: 1, max_body: 64, ..RetryPolicy::new(2) }
+}
+
+#[tokio::test]
+async fn savepoint_rollback_keeps_outer_writes() {
+    let db = test_db("txnmw_savepoint", &[DOCS_TABLE]).await;
+    let log = TxnLog::new();
+
+    handler_with_tx_logged(&db, &log, |mut sw| async move {
+        insert(sw.get(), &doc("outer", 0)).await.unwrap();
+
+        let sp = sw.savepoint().await.unwrap();
+        insert(sw.get(), &doc("undone", 0)).await.unwrap();
+        sw.rollback_to(sp).await.unwrap();
+
+        let sp = sw.savepoint().await.unwrap();
+        insert(sw.get(), &doc("kept", 0)).await.unwrap();
+        sw.release(sp).await.unwrap();
+    }).await;
+
+    assert_eq!(log.states(), [TxnState::SavepointStarted, TxnState::SavepointRolledBack, TxnState::SavepointStarted, TxnState::SavepointReleased]);
+    let mut txn = db.newtx_read().await.unwrap();
+    let mut ids: Vec<String> = find_all_by_field::<Doc, _, _>(&mut txn, "version", &0i64).await.unwrap()
+        .into_iter().map(|it| it.id).collect();
+    ids.sort();
+    assert_eq!(ids, ["kept", "outer"]);
+}
+
+#[actix_web::test]
+async fn busy_handler_is_replayed() {
+    let db = test_db("txnmw_replay", &[DOCS_TABLE]).await;
+    let log = TxnLog::new();
+    let calls = calls(1);
+    let app = test::init_service(App::new()
+        .app_data(web::Data::new(db.clone()))
+        .app_data(web::Data::new(log.clone()))
+        .app_data(calls.clone())
+        .wrap(TxnMidFactory::new(db.clone()).with_retry(retry()))
+        .route("/docs/{id}", web::put().to(put_doc))).await;
+
+    let req = test::TestRequest::put().uri("/docs/replayed").set_payload("replayed body").to_request();
+    let res = app.call(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(calls.handler.load(Ordering::SeqCst), 2);
+    assert_eq!(calls.rolled_back.load(Ordering::SeqCst), 1);
+    assert_eq!(log.states(), [TxnState::Nonexistent, TxnState::Started, TxnState::RolledBack, TxnState::Nonexistent, TxnState::Started, TxnState::Committed]);
+
+    // the second attempt saw the same path and body, the first one's insert was rolled back
+    let mut txn = db.newtx_read().await.unwrap();
+    assert_eq!(find_by_id::<Doc, _>(&mut txn, "replayed").await.unwrap().map(|it| it.body), Some(String::from("replayed body")));
+}
+
+#[actix_web::test]
+async fn busy_until_retries_run_out() {
+    let db = test_db("txnmw_exhausted", &[DOCS_TABLE]).await;
+    let calls = calls(u32::MAX);
+    let app = test::init_service(App::new()
+        .app_data(web::Data::new(db.clone()))
+        .app_data(calls.clone())
+        .wrap(TxnMidFactory::new(db.clone()).with_retry(retry()))
+        .route("/docs/{id}", web::put().to(put_doc))).await;
+
+    let req = test::TestRequest::put().uri("/docs/never").set_payload("body").to_request();
+    let err = app.call(req).await.map(|_| ()).unwrap_err();
+    assert_eq!(err.error_response().status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(err.to_string(), "LockError");
+    // the first attempt and 2 retries, each rolled back
+    assert_eq!(calls.handler.load(Ordering::SeqCst), 3);
+    assert_eq!(calls.rolled_back.load(Ordering::SeqCst), 3);
+
+    let mut txn = db.newtx_read().await.unwrap();
+    assert_eq!(count::<Doc, _>(&mut txn).await.unwrap(), 0);
+}
+
+#[actix_web::test]
+async fn replayable_body_is_limited() {
+    let db = test_db("txnmw_too_large", &[DOCS_TABLE]).await;
+    let calls = calls(0);
+    let app = test::init_service(App::new()
+        .app_data(web::Data::new(db.clone()))
+        .app_data(calls.clone())
+        .wrap(TxnMidFactory::new(db.clone()).with_retry(retry()))
+        .route("/docs/{id}", web::put().to(put_doc))).await;
+
+    let req = test::TestRequest::put().uri("/docs/large").set_payload("x".repeat(65)).to_request();
+    let err = app.call(req).await.map(|_| ()).unwrap_err();
+    assert_eq!(err.error_response().status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(calls.handler.load(Ordering::SeqCst), 0);
+}
+
+#[actix_web::test]
+async fn commit_by_status_and_decision() {
+    let db = test_db("txnmw_decision", &[DOCS_TABLE]).await;
+    let log = TxnLog::new();
+    let app = test::init_service(App::new()
+        .app_data(web::Data::new(db.clone()))
+        .app_data(web::Data::new(log.clone()))
+        .wrap(TxnMidFactory::new(db.clone()))
+        .route("/docs/{id}", web::put().to(write_doc))
+        .route("/plain", web::get().to(HttpResponse::Ok))).await;
+
+    for (id, committed) in [("saved", true), ("rejected", false), ("kept", true), ("discarded", false)] {
+        let req = test::TestRequest::put().uri(&format!("/docs/{id}")).to_request();
+        app.call(req).await.unwrap();
+        assert!(if committed { expect_committed(&log) } else { expect_rolled_back(&log) }, "{id}");
+        log.take();
+    }
+    app.call(test::TestRequest::get().uri("/plain").to_request()).await.unwrap();
+    assert!(expect_no_txn(&log));
+
+    let mut txn = db.newtx_read().await.unwrap();
+    let mut ids: Vec<String> = find_all_by_field::<Doc, _, _>(&mut txn, "version", &0i64).await.unwrap()
+        .into_iter().map(|it| it.id).collect();
+    ids.sort();
+    assert_eq!(ids, ["kept", "saved"]);
+}
+
+#[actix_web::test]
+async fn audit_time_from_app_clock() {
+    let db = test_db("txnmw_audit", &[DOCS_TABLE, ROW_AUDIT_SCHEMA.on::<TestBackend>()]).await;
+    let objs = TestApp::new();
+    let at = objs.advance(500);
+    let app = test::init_service(App::new()
+        .app_data(web::Data::new(db.clone()))
+        .app_data(objs.clone())
+        .wrap(TxnMidFactory::new(db.clone()).with_audit::<TestApp>())
+        .route("/docs/{id}", web::put().to(write_doc))).await;
+
+    let res = app.call(test::TestRequest::put().uri("/docs/audited").to_request()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let mut txn = db.newtx_read().await.unwrap();
+    let created: Vec<i64> = sqlx::query_scalar("SELECT created FROM row_audit WHERE row_id = $1")
+        .bind("audited")
+        .fetch_all(&mut *txn).await.unwrap();
+    assert_eq!(created, [at]);
+}
+
+async fn change_doc(mut txn: WriteTxn<'_, TestBackend>, _principal: PrincipalAny, path: web::Path<(String, String)>) -> Result<HttpResponse, AnyHandlerError> {
+    let (action, id) = path.into_inner();
+    match action.as_str() {
+        "update" => { update_fields::<Doc, _>(txn.get(), &id, Assignments::new().set("body", "changed")).await?; },
+        "delete" => { delete_by_id::<Doc, _>(txn.get(), &id).await?; },
+        "soft_delete" => { soft_delete::<Doc, _>(txn.get(), &id, 100).await?; },
+        _ => { upsert(txn.get(), &Doc { body: String::from("upserted"), ..doc(&id, 0) }).await?; },
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+// action, old_values, new_values, principal, impersonator, request_id
+type AuditEntry<V = serde_json::Value> = (String, Option<V>, Option<V>, Option<String>, Option<String>, Option<String>);
+
+async fn audit_entry(db: &DbMain<TestBackend>, row_id: &str) -> AuditEntry {
+    let mut txn = db.newtx_read().await.unwrap();
+    let (action, old, new, principal, impersonator, rid): AuditEntry<String> =
+        sqlx::query_as("SELECT action, old_values, new_values, principal, impersonator, request_id FROM row_audit WHERE row_id = $1")
+            .bind(row_id)
+            .fetch_one(&mut *txn).await.unwrap();
+    let json = |it: Option<String>| it.map(|it| serde_json::from_str(&it).unwrap());
+    (action, json(old), json(new), principal, impersonator, rid)
+}
+
+#[actix_web::test]
+async fn audit_entries_carry_the_request() {
+    const ADMIN: &str = "admin@bear.test";
+    const USER: &str = "user@bear.test";
+    let db = test_db("txnmw_audit_entries", &[DOCS_TABLE, SESSIONS_TABLE, ROW_AUDIT_SCHEMA.on::<TestBackend>(), IMPERSONATION_AUDIT_SCHEMA.on::<TestBackend>()]).await;
+    let objs = TestApp::with_sessions(&[ADMIN]);
+    let mut txn = db.newtx_write().await.unwrap();
+    for id in ["a", "b", "c", "d"] {
+        insert(&mut txn, &doc(id, 0)).await.unwrap();
+    }
+    txn.commit().await.unwrap();
+    let app = test::init_service(App::new()
+        .app_data(web::Data::new(db.clone()))
+        .app_data(objs.clone())
+        .wrap(AuthMidFactory::<TestApp<TestSession>>::new(db.clone(), false))
+        .wrap(TxnMidFactory::new(db.clone()).with_audit::<TestApp<TestSession>>())
+        .route("/docs/{action}/{id}", web::put().to(change_doc))).await;
+
+    let user = login(&db, &objs, USER).await;
+    let admin = login(&db, &objs, ADMIN).await;
+    let target = PrincipalInner { auth_kind: String::from("Oidc"), principal: String::from(USER), parent: None, impersonator: Some(String::from(ADMIN)) };
+    let impersonated = TestSession::new_impersonation(objs.advance(0) + 60, target, admin);
+    let mut txn = db.newtx_write().await.unwrap();
+    TestSession::insert(&mut txn, &impersonated).await.unwrap();
+    txn.commit().await.unwrap();
+
+    for (path, code) in [("update/a", &user), ("delete/b", &impersonated.code), ("soft_delete/c", &impersonated.code), ("upsert/d", &user)] {
+        let req = test::TestRequest::put().uri(&format!("/docs/{path}"))
+            .cookie(Cookie::new(SESSION_COOKIE_NAME, code.clone()))
+            .insert_header((REQUEST_ID_HEADER, format!("req-{path}")))
+            .to_request();
+        assert_eq!(app.call(req).await.unwrap().status(), StatusCode::OK, "{path}");
+    }
+
+    let by_user = Some(format!("Oidc:{USER}"));
+    let by_admin = Some(String::from(ADMIN));
+    assert_eq!(audit_entry(&db, "a").await, (String::from("update"),
+        Some(serde_json::json!({ "body": "body a" })), Some(serde_json::json!({ "body": "changed" })),
+        by_user.clone(), None, Some(String::from("req-update/a"))));
+    assert_eq!(audit_entry(&db, "b").await, (String::from("delete"),
+        Some(serde_json::json!({ "id": "b", "body": "body b", "version": 0, "deleted_at": null })), None,
+        by_user.clone(), by_admin.clone(), Some(String::from("req-delete/b"))));
+    assert_eq!(audit_entry(&db, "c").await, (String::from("soft_delete"),
+        Some(serde_json::json!({ "deleted_at": null })), Some(serde_json::json!({ "deleted_at": 100 })),
+        by_user.clone(), by_admin, Some(String::from("req-soft_delete/c"))));
+    // only the changed columns
+    assert_eq!(audit_entry(&db, "d").await, (String::from("upsert"),
+        Some(serde_json::json!({ "body": "body d" })), Some(serde_json::json!({ "body": "upserted" })),
+        by_user, None, Some(String::from("req-upsert/d"))));
+}
+
+// writes when the id isn't taken yet, "skip" never begins a transaction
+async fn read_then_write_put(mut txn: ReadThenWriteTxn<'_, TestBackend>, id: web::Path<String>, calls: web::Data<Calls>) -> Result<HttpResponse, AnyHandlerError> {
+    let hook_calls = calls.clone();
+    txn.after_commit(async move {
+        hook_calls.committed.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    });
+    let hook_calls = calls.clone();
+    txn.after_rollback(async move {
+        hook_calls.rolled_back.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    });
+    match id.as_str() {
+        "skip" => return Ok(HttpResponse::Ok().finish()),
+        "skip-rejected" => return Ok(HttpResponse::BadRequest().finish()),
+        _ => {}
+    }
+    if find_by_id::<Doc, _>(txn.read().await?, &id).await?.is_some() {
+        return Ok(HttpResponse::Conflict().finish())
+    }
+    assert!(!txn.is_writing());
+    insert(txn.write().await?, &doc(&id, 0)).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[actix_web::test]
+async fn read_then_write_hooks_without_txn() {
+    let db = test_db("txnmw_rtw_unused", &[DOCS_TABLE]).await;
+    let log = TxnLog::new();
+    let calls = calls(0);
+    let app = test::init_service(App::new()
+        .app_data(web::Data::new(db.clone()))
+        .app_data(web::Data::new(log.clone()))
+        .app_data(calls.clone())
+        .wrap(TxnMidFactory::new(db.clone()))
+        .route("/rtw/{id}", web::put().to(read_then_write_put))).await;
+
+    let res = app.call(test::TestRequest::put().uri("/rtw/skip").to_request()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(expect_no_txn(&log));
+    assert_eq!(calls.committed.load(Ordering::SeqCst), 1);
+    assert_eq!(calls.rolled_back.load(Ordering::SeqCst), 0);
+
+    let res = app.call(test::TestRequest::put().uri("/rtw/skip-rejected").to_request()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert!(expect_no_txn(&log));
+    assert_eq!(calls.committed.load(Ordering::SeqCst), 1);
+    assert_eq!(calls.rolled_back.load(Ordering::SeqCst), 1);
+}
+
+#[actix_web::test]
+async fn read_then_write_are_separate_txns() {
+    let db = test_db("txnmw_rtw_upgrade", &[DOCS_TABLE]).await;
+    let log = TxnLog::new();
+    let calls = calls(0);
+    let app = test::init_service(App::new()
+        .app_data(web::Data::new(db.clone()))
+        .app_data(web::Data::new(log.clone()))
+        .app_data(calls.clone())
+        .wrap(TxnMidFactory::new(db.clone()))
+        .route("/rtw/{id}", web::put().to(read_then_write_put))).await;
+
+    let res = app.call(test::TestRequest::put().uri("/rtw/upgraded").to_request()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    // the reader is closed before the writer begins
+    assert_eq!(log.take(), [TxnState::Nonexistent, TxnState::Started, TxnState::Committed, TxnState::Started, TxnState::Committed]);
+    assert_eq!(calls.committed.load(Ordering::SeqCst), 1);
+
+    // only read, then rolled back by the status
+    let res = app.call(test::TestRequest::put().uri("/rtw/upgraded").to_request()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    assert_eq!(log.take(), [TxnState::Nonexistent, TxnState::Started, TxnState::RolledBack]);
+    assert_eq!(calls.rolled_back.load(Ordering::SeqCst), 1);
+
+    let mut txn = db.newtx_read().await.unwrap();
+    assert_eq!(count::<Doc, _>(&mut txn).await.unwrap(), 1);
+}
@@ -0,0 +1,79 @@
+// Prefixed typed ids: the prefix is checked on every way in.
+
+mod common;
+
+use bear::errors::ApiError;
+use bear::typed_id;
+use bear::utils::gen_sortable_token;
+use bear::db::*;
+use common::{doc, test_db, DOCS_TABLE};
+
+typed_id!(OrderId, "ord");
+typed_id!(EventId, "evt", sortable);
+
+fn invalid(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<ApiError>() == Some(&ApiError::Any(String::from("ord.id.invalid")))
+}
+
+#[test]
+fn prefix_checked_on_parse() {
+    let id = OrderId::new();
+    assert!(id.as_ref().starts_with("ord_"));
+    assert_eq!(OrderId::parse(id.as_ref()).unwrap(), id);
+    assert_eq!("ord_7xK2".parse::<OrderId>().unwrap().as_ref(), "ord_7xK2");
+
+    for bad in ["usr_7xK2", "ord7xK2", "ord_", "ord_7x-K2", "ordr_7xK2", "_7xK2", ""] {
+        assert!(invalid(&OrderId::parse(bad).unwrap_err()), "{bad}");
+    }
+}
+
+#[test]
+fn prefix_checked_on_serde() {
+    let id = OrderId::parse("ord_7xK2").unwrap();
+    assert_eq!(serde_json::to_string(&id).unwrap(), "\"ord_7xK2\"");
+    assert_eq!(serde_json::from_str::<OrderId>("\"ord_7xK2\"").unwrap(), id);
+
+    let err = serde_json::from_str::<OrderId>("\"usr_7xK2\"").unwrap_err();
+    assert!(err.to_string().contains("ord.id.invalid"), "{err}");
+    assert!(serde_json::from_str::<OrderId>("\"evt_7xK2\"").is_err());
+}
+
+#[tokio::test]
+async fn prefix_checked_on_decode() {
+    let db = test_db("typed_id_decode", &[DOCS_TABLE]).await;
+    let mut txn = db.newtx_write().await.unwrap();
+    insert(&mut txn, &doc("ord_7xK2", 0)).await.unwrap();
+    insert(&mut txn, &doc("usr_7xK2", 0)).await.unwrap();
+
+    let found: OrderId = sqlx::query_scalar("SELECT id FROM docs WHERE id = $1")
+        .bind("ord_7xK2")
+        .fetch_one(txn.conn()).await.unwrap();
+    assert_eq!(found.as_ref(), "ord_7xK2");
+
+    let err = sqlx::query_scalar::<_, OrderId>("SELECT id FROM docs WHERE id = $1")
+        .bind("usr_7xK2")
+        .fetch_one(txn.conn()).await.unwrap_err();
+    assert!(matches!(err, sqlx::Error::ColumnDecode { .. }), "{err:?}");
+    assert!(err.to_string().contains("ord.id.invalid"), "{err}");
+    txn.rollback().await.unwrap();
+}
+
+#[tokio::test]
+async fn sortable_tokens_follow_creation_time() {
+    let mut tokens = Vec::new();
+    for _ in 0..5 {
+        tokens.push(gen_sortable_token());
+        // the random part only orders tokens of the same millisecond
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+    }
+    assert!(tokens.iter().all(|it| it.len() == 26));
+    let mut sorted = tokens.clone();
+    sorted.sort();
+    assert_eq!(sorted, tokens);
+
+    let first = EventId::new();
+    tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+    let second = EventId::new();
+    assert!(first.as_ref() < second.as_ref());
+    assert_eq!(EventId::parse(second.as_ref()).unwrap(), second);
+}
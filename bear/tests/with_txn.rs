@@ -0,0 +1,81 @@
+// Transactions outside of requests: DbMain::with_write/with_read and testbase::with_tx.
+
+mod common;
+
+use bear::errors::ApiError;
+use bear::db::*;
+use bear::testbase::with_tx;
+use bear::txnmw::RetryPolicy;
+use common::{doc, test_db, Doc, TestBackend, DOCS_TABLE};
+
+// what a job would call, borrowing the transaction
+async fn add_doc(txn: &mut DbWriteTxn<'_, TestBackend>, id: &str) -> anyhow::Result<i64> {
+    insert(txn, &doc(id, 0)).await?;
+    count::<Doc, _>(txn).await
+}
+
+async fn ids(db: &DbMain<TestBackend>) -> Vec<String> {
+    db.with_read(|txn| Box::pin(async move {
+        let mut ids: Vec<String> = find_all_by_field::<Doc, _, _>(txn, "version", &0i64).await?
+            .into_iter().map(|it| it.id).collect();
+        ids.sort();
+        Ok(ids)
+    })).await.unwrap()
+}
+
+#[tokio::test]
+async fn commits_on_ok_rolls_back_on_err() {
+    let db = test_db("with_txn_close", &[DOCS_TABLE]).await;
+
+    let n = db.with_write(|txn| Box::pin(async move { add_doc(txn, "kept").await })).await.unwrap();
+    assert_eq!(n, 1);
+
+    let err = db.with_write(|txn| Box::pin(async move {
+        add_doc(txn, "undone").await?;
+        Err::<(), _>(ApiError::InvalidInput.into())
+    })).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<ApiError>(), Some(&ApiError::InvalidInput));
+
+    with_tx(&db, |txn| Box::pin(async move { add_doc(txn, "from_test").await })).await;
+    assert_eq!(ids(&db).await, ["from_test", "kept"]);
+}
+
+#[tokio::test]
+async fn replays_on_busy() {
+    let db = test_db("with_txn_retry", &[DOCS_TABLE]).await;
+    let retry = RetryPolicy { backoff_ms: 1, max_backoff_ms: 1, ..RetryPolicy::new(2) };
+
+    let mut attempts = 0;
+    let n = db.with_write_retry(&retry, |txn| {
+        attempts += 1;
+        let attempt = attempts;
+        Box::pin(async move {
+            let n = add_doc(txn, "replayed").await?;
+            if attempt == 1 { return Err(sqlx::Error::PoolTimedOut.into()) }
+            Ok(n)
+        })
+    }).await.unwrap();
+    // the insert of the first attempt was rolled back
+    assert_eq!((attempts, n), (2, 1));
+
+    let mut attempts = 0;
+    let err = db.with_write_retry(&retry, |txn| {
+        attempts += 1;
+        Box::pin(async move {
+            add_doc(txn, "never").await?;
+            Err::<(), _>(sqlx::Error::PoolTimedOut.into())
+        })
+    }).await.unwrap_err();
+    assert_eq!(attempts, 3);
+    assert_eq!(err.downcast_ref::<ApiError>(), Some(&ApiError::LockError));
+
+    // without a policy the busy error is not retried
+    let mut attempts = 0;
+    db.with_write(|_txn| {
+        attempts += 1;
+        Box::pin(async move { Err::<(), _>(sqlx::Error::PoolTimedOut.into()) })
+    }).await.unwrap_err();
+    assert_eq!(attempts, 1);
+
+    assert_eq!(ids(&db).await, ["replayed"]);
+}